@@ -1,16 +1,22 @@
 use crate::config::ConfigError::{ParseBindAddress, ParseBool, ParsePort};
+use crate::util::is_e164;
 use crate::{opsgenie, twilio};
+use chrono::{DateTime, FixedOffset, Utc};
 use hyper::header::{HeaderValue, InvalidHeaderValue};
 use secrecy::{CloneableSecret, DebugSecret, Secret, Zeroize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use serde::{Deserialize, Serialize};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::collections::HashMap;
 use std::env;
 use std::env::VarError;
 use std::ffi::OsString;
 use std::fmt::Debug;
 use std::net::{AddrParseError, IpAddr, Ipv4Addr};
 use std::num::ParseIntError;
+use std::path::PathBuf;
 use std::str::{FromStr, ParseBoolError};
-use tracing::instrument;
+use std::time::Duration;
+use tracing::{instrument, Level};
 use url::Url;
 
 static TRACE_EXPORTER_ENVNAME: &str = "WYGC_ENABLE_TRACE_EXPORT";
@@ -25,15 +31,127 @@ static BIND_ADDRESS_DEFAULT: &str = "0.0.0.0";
 static BIND_PORT_ENVNAME: &str = "WYGC_BIND_PORT";
 static BIND_PORT_DEFAULT: &str = "2368";
 
+static MAX_CONCURRENT_UPSTREAM_REQUESTS_ENVNAME: &str = "WYGC_MAX_CONCURRENT_UPSTREAM_REQUESTS";
+static MAX_CONCURRENT_UPSTREAM_REQUESTS_DEFAULT: u64 = 50;
+
+static READINESS_GRACE_PERIOD_ENVNAME: &str = "WYGC_READINESS_GRACE_PERIOD_SECONDS";
+static READINESS_GRACE_PERIOD_DEFAULT: u64 = 30;
+
+static WHOSONCALL_LOG_LEVEL_ENVNAME: &str = "WYGC_WHOSONCALL_LOG_LEVEL";
+static ALERT_LOG_LEVEL_ENVNAME: &str = "WYGC_ALERT_LOG_LEVEL";
+static REQUEST_LOG_LEVEL_DEFAULT: Level = Level::INFO;
+
+static RESPONSE_ENVELOPE_ENVNAME: &str = "WYGC_RESPONSE_ENVELOPE";
+static RESPONSE_ENVELOPE_DEFAULT: ResponseEnvelope = ResponseEnvelope::Bare;
+static WHOSONCALL_VIEW_ENVNAME: &str = "WYGC_WHOSONCALL_VIEW";
+static WHOSONCALL_VIEW_DEFAULT: WhosOnCallView = WhosOnCallView::Full;
+
+static WHOSONCALL_MAX_ENTRIES_ENVNAME: &str = "WYGC_WHOSONCALL_MAX_ENTRIES";
+
+static STATUS_FORMAT_ENVNAME: &str = "WYGC_STATUS_FORMAT";
+static STATUS_FORMAT_DEFAULT: StatusFormat = StatusFormat::Json;
+
+static SOURCE_RATE_LIMIT_ENVNAME: &str = "WYGC_SOURCE_RATE_LIMIT";
+static SOURCE_RATE_LIMIT_WINDOW_ENVNAME: &str = "WYGC_SOURCE_RATE_LIMIT_WINDOW_SECONDS";
+static SOURCE_RATE_LIMIT_WINDOW_DEFAULT: u64 = 60;
+static TRUST_FORWARDED_HEADERS_ENVNAME: &str = "WYGC_TRUST_FORWARDED_HEADERS";
+static TRUST_FORWARDED_HEADERS_DEFAULT: bool = false;
+
+static IDEMPOTENCY_KEY_TTL_ENVNAME: &str = "WYGC_IDEMPOTENCY_KEY_TTL_SECONDS";
+static IDEMPOTENCY_KEY_TTL_DEFAULT: u64 = 86400;
+
+static ALERT_PROFILES_ENVNAME: &str = "WYGC_ALERT_PROFILES";
+static SCHEDULE_PROFILES_ENVNAME: &str = "WYGC_SCHEDULE_PROFILES";
+// Marks a profile whose alerts don't count against the caller's rate limit
+static ALERT_PROFILE_UNLIMITED: &str = "unlimited";
+
+static MAINTENANCE_WINDOWS_ENVNAME: &str = "WYGC_MAINTENANCE_WINDOWS";
+static MAINTENANCE_NOTIFY_ENVNAME: &str = "WYGC_MAINTENANCE_NOTIFY_SLACK";
+static MAINTENANCE_NOTIFY_DEFAULT: bool = false;
+
+static STARTUP_SELF_TEST_ENVNAME: &str = "WYGC_STARTUP_SELF_TEST";
+static STARTUP_SELF_TEST_DEFAULT: SelfTestMode = SelfTestMode::Off;
+
 static TWILIO_TOKEN_ENVNAME: &str = "WYGC_TWILIO_TOKEN";
 static TWILIO_BASEURL_ENVNAME: &str = "WYGC_TWILIO_BASEURL";
 static TWILIO_BASEURL_DEFAULT: &str = "https://studio.twilio.com/v2/Flows/";
 static TWILIO_WORKFLOW_ENVNAME: &str = "WYGC_TWILIO_WORKFLOW";
 static TWILIO_OUTGOING_NUMBER_ENVNAME: &str = "WYGC_TWILIO_OUTNUMBER";
+static TWILIO_INVALID_NUMBER_POLICY_ENVNAME: &str = "WYGC_INVALID_NUMBER_POLICY";
+static TWILIO_INVALID_NUMBER_POLICY_DEFAULT: InvalidNumberPolicy = InvalidNumberPolicy::Skip;
+static TWILIO_CALLER_ID_ENVNAME: &str = "WYGC_TWILIO_CALLER_ID";
+static TWILIO_ACCOUNT_SID_ENVNAME: &str = "WYGC_TWILIO_ACCOUNT_SID";
+static TWILIO_API_BASEURL_ENVNAME: &str = "WYGC_TWILIO_API_BASEURL";
+static TWILIO_API_BASEURL_DEFAULT: &str = "https://api.twilio.com/2010-04-01/";
+static TWILIO_SCHEDULE_PARAMETERS_ENVNAME: &str = "WYGC_TWILIO_SCHEDULE_PARAMETERS";
+static TWILIO_ERROR_CODES_ENVNAME: &str = "WYGC_ALERT_ERROR_CODES";
+static TWILIO_ERROR_CODES_DEFAULT: bool = false;
+static TWILIO_SMS_FALLBACK_TO_VOICE_ENVNAME: &str = "WYGC_SMS_FALLBACK_TO_VOICE";
+static TWILIO_SMS_FALLBACK_TO_VOICE_DEFAULT: bool = false;
+static TWILIO_ALERT_CHANNEL_ENVNAME: &str = "WYGC_ALERT_CHANNEL";
+static TWILIO_ALERT_CHANNEL_DEFAULT: AlertChannel = AlertChannel::Voice;
+static TWILIO_PRIORITY_CHANNELS_ENVNAME: &str = "WYGC_PRIORITY_CHANNELS";
+// Urgent alerts call people, less urgent ones only send a message
+static TWILIO_PRIORITY_CHANNELS_DEFAULT: [(&str, AlertChannel); 5] = [
+    ("P1", AlertChannel::Voice),
+    ("P2", AlertChannel::Voice),
+    ("P3", AlertChannel::Voice),
+    ("P4", AlertChannel::Sms),
+    ("P5", AlertChannel::Sms),
+];
+static TWILIO_PAGING_BUDGET_ENVNAME: &str = "WYGC_PAGING_BUDGET_CALLS";
+static TWILIO_PAGING_BUDGET_WINDOW_ENVNAME: &str = "WYGC_PAGING_BUDGET_WINDOW_SECONDS";
+static TWILIO_PAGING_BUDGET_WINDOW_DEFAULT: u64 = 3600;
+static TWILIO_SHARED_NUMBERS_INDIVIDUALLY_ENVNAME: &str = "WYGC_TREAT_SHARED_NUMBERS_INDIVIDUALLY";
+static TWILIO_SHARED_NUMBERS_INDIVIDUALLY_DEFAULT: bool = false;
 
 static OPSGENIE_TOKEN_ENVNAME: &str = "WYGC_OPSGENIE_TOKEN";
 static OPSGENIE_BASEURL_ENVNAME: &str = "WYGC_OPSGENIE_BASEURL";
-static OPSGENIE_BASEURL_DEFAULT: &str = "https://api.opsgenie.com/v2/";
+static OPSGENIE_BASEURL_DEFAULT: &str = "https://api.opsgenie.com";
+static OPSGENIE_API_VERSION_ENVNAME: &str = "WYGC_OPSGENIE_API_VERSION";
+static OPSGENIE_API_VERSION_DEFAULT: OpsgenieApiVersion = OpsgenieApiVersion::V2;
+static OPSGENIE_ROLE_FILTER_ENVNAME: &str = "WYGC_OPSGENIE_ROLE_FILTER";
+static OPSGENIE_SCHEDULE_GROUPS_ENVNAME: &str = "WYGC_SCHEDULE_GROUPS";
+static OPSGENIE_EXTRA_QUERY_PARAMS_ENVNAME: &str = "WYGC_OPSGENIE_EXTRA_QUERY_PARAMS";
+// These are set by us on the requests to OpsGenie, allowing them to be overridden would break the
+// parsing of the responses
+static OPSGENIE_RESERVED_QUERY_PARAMS: [&str; 4] =
+    ["flat", "scheduleIdentifierType", "identifierType", "expand"];
+static OPSGENIE_ALERT_ON_TOTAL_FAILURE_ENVNAME: &str = "WYGC_OPSGENIE_ALERT_ON_TOTAL_FAILURE";
+static OPSGENIE_ALERT_ON_TOTAL_FAILURE_DEFAULT: bool = false;
+static OPSGENIE_ALERT_PRIORITY_ENVNAME: &str = "WYGC_OPSGENIE_ALERT_PRIORITY";
+static OPSGENIE_ALERT_PRIORITY_DEFAULT: &str = "P1";
+static OPSGENIE_ALERT_PRIORITIES: [&str; 5] = ["P1", "P2", "P3", "P4", "P5"];
+static OPSGENIE_MAX_SCHEDULES_PER_PAGE_ENVNAME: &str = "WYGC_SCHEDULES_MAX_LIMIT";
+static OPSGENIE_MAX_SCHEDULES_PER_PAGE_DEFAULT: u64 = 100;
+static OPSGENIE_USER_IDENTIFIER_TYPE_ENVNAME: &str = "WYGC_OPSGENIE_USER_IDENTIFIER_TYPE";
+static OPSGENIE_USER_IDENTIFIER_TYPE_DEFAULT: UserIdentifierType = UserIdentifierType::Username;
+static OPSGENIE_PRESERVE_RECIPIENT_ORDER_ENVNAME: &str = "WYGC_OPSGENIE_PRESERVE_RECIPIENT_ORDER";
+static OPSGENIE_PRESERVE_RECIPIENT_ORDER_DEFAULT: bool = true;
+static OPSGENIE_EXPAND_TEAMS_ENVNAME: &str = "WYGC_OPSGENIE_EXPAND_TEAMS";
+static OPSGENIE_EXPAND_TEAMS_DEFAULT: bool = false;
+static OPSGENIE_MAX_TEAM_SIZE_ENVNAME: &str = "WYGC_OPSGENIE_MAX_TEAM_SIZE";
+static OPSGENIE_MAX_TEAM_SIZE_DEFAULT: u64 = 10;
+static OPSGENIE_FAIL_ON_UNEXPANDED_CONTACTS_ENVNAME: &str =
+    "WYGC_OPSGENIE_FAIL_ON_UNEXPANDED_CONTACTS";
+static OPSGENIE_FAIL_ON_UNEXPANDED_CONTACTS_DEFAULT: bool = false;
+static OPSGENIE_METADATA_REFRESH_ENVNAME: &str = "WYGC_OPSGENIE_METADATA_REFRESH_SECONDS";
+static OPSGENIE_METADATA_REFRESH_DEFAULT: u64 = 3600;
+
+static ALERT_HISTORY_FILE_ENVNAME: &str = "WYGC_ALERT_HISTORY_FILE";
+static ALERT_HISTORY_MAX_BYTES_ENVNAME: &str = "WYGC_ALERT_HISTORY_MAX_BYTES";
+static ALERT_HISTORY_MAX_BYTES_DEFAULT: u64 = 10 * 1024 * 1024;
+static ALERT_HISTORY_MAX_FILES_ENVNAME: &str = "WYGC_ALERT_HISTORY_MAX_FILES";
+static ALERT_HISTORY_MAX_FILES_DEFAULT: u64 = 3;
+static ALERT_HISTORY_RECENT_ENTRIES_ENVNAME: &str = "WYGC_ALERT_HISTORY_RECENT_ENTRIES";
+static ALERT_HISTORY_RECENT_ENTRIES_DEFAULT: u64 = 100;
+
+static ALERT_MODE_ENVNAME: &str = "WYGC_ALERT_MODE";
+static ALERT_QUEUE_DIR_ENVNAME: &str = "WYGC_ALERT_QUEUE_DIR";
+static EXTERNAL_BASE_URL_ENVNAME: &str = "WYGC_EXTERNAL_BASE_URL";
+
+static HEARTBEAT_WINDOW_ENVNAME: &str = "WYGC_HEARTBEAT_WINDOW_SECONDS";
+static HEARTBEAT_WEBHOOK_ENVNAME: &str = "WYGC_HEARTBEAT_WEBHOOK_URL";
 
 static SLACK_TOKEN_ENVNAME: &str = "WYGC_SLACK_TOKEN";
 static SLACK_BASEURL_ENVNAME: &str = "WYGC_SLACK_BASEURL";
@@ -97,6 +215,63 @@ pub enum ConfigError {
     },
     #[snafu(display("failed to parse boolean value for [{envname}]: \n{source}"))]
     ConvertEnvString { source: VarError, envname: String },
+    #[snafu(display(
+        "failed to parse schedule group [{entry}] from [{envname}], expected the format `group=primary:backup`"
+    ))]
+    ParseScheduleGroup { entry: String, envname: String },
+    #[snafu(display(
+        "failed to parse query parameter [{entry}] from [{envname}], expected the format `key=value`"
+    ))]
+    ParseQueryParameter { entry: String, envname: String },
+    #[snafu(display(
+        "failed to parse priority channel [{entry}] from [{envname}], expected the format `priority=channel`"
+    ))]
+    ParsePriorityChannel { entry: String, envname: String },
+    #[snafu(display(
+        "failed to parse maintenance window [{entry}] from [{envname}], expected the format `start/end` or `start/end@schedule` with RFC 3339 timestamps that include a fixed UTC offset, time zone names are not supported, and start before end"
+    ))]
+    ParseMaintenanceWindow { entry: String, envname: String },
+    #[snafu(display(
+        "query parameter [{parameter}] from [{envname}] is always set by this service and cannot be overridden"
+    ))]
+    ReservedQueryParameter { parameter: String, envname: String },
+    #[snafu(display(
+        "invalid OpsGenie alert priority [{priority}] in [{envname}], expected one of P1 to P5"
+    ))]
+    InvalidAlertPriority { priority: String, envname: String },
+    #[snafu(display(
+        "caller id [{caller_id}] from [{envname}] is not a phone number in E.164 format, Twilio only allows numbers owned by or verified for the account"
+    ))]
+    InvalidCallerId { caller_id: String, envname: String },
+    #[snafu(display("invalid value [{value}] for [{envname}], expected one of: {expected}"))]
+    InvalidValue {
+        value: String,
+        envname: String,
+        expected: String,
+    },
+    #[snafu(display("failed to parse numeric value for [{envname}]: \n{source}"))]
+    ParseNumber {
+        source: ParseIntError,
+        envname: String,
+    },
+    #[snafu(display("[{envname}] has to be greater than 0"))]
+    ZeroValue { envname: String },
+    #[snafu(display("[{envname}] has to be set when alerts are queued"))]
+    MissingAlertQueueDir { envname: String },
+    #[snafu(display(
+        "failed to parse alert profile [{entry}] from [{envname}], expected the format `name=channel` or `name=channel:unlimited` with a channel of voice, sms or both"
+    ))]
+    ParseAlertProfile { entry: String, envname: String },
+    #[snafu(display(
+        "failed to parse schedule profile [{entry}] from [{envname}], expected the format `schedule=profile`"
+    ))]
+    ParseScheduleProfile { entry: String, envname: String },
+    #[snafu(display("unknown alert profile [{profile}] in [{envname}]"))]
+    UnknownAlertProfile { profile: String, envname: String },
+    #[snafu(display(
+        "[{envname}] requires [{required}] to be `true`, callers can only be told apart by the client address a proxy reports"
+    ))]
+    SourceRateLimitWithoutForwardedHeaders { envname: String, required: String },
 }
 
 #[derive(Debug, Clone)]
@@ -104,10 +279,256 @@ pub struct Config {
     pub bind_address: IpAddr,
     pub bind_port: u16,
 
+    pub response_envelope: ResponseEnvelope,
+    pub whosoncall_view: WhosOnCallView,
+    // Upper limit for the people listed by `/whosoncall`, alerts always go to everybody
+    pub whosoncall_max_entries: Option<usize>,
+
+    // Shape of the `/status` response
+    pub status_format: StatusFormat,
+
+    // Level at which successfully handled requests are logged, errors are always logged as warnings
+    pub whosoncall_log_level: Level,
+    pub alert_log_level: Level,
+
+    // Readiness check failures right after startup are reported as `starting` instead of `sick`
+    pub readiness_grace_period: Duration,
+
+    // Shared budget for phone number lookups and Twilio calls across all requests
+    pub max_concurrent_upstream_requests: usize,
+
+    // Maximum number of `/alert` requests per caller within `source_rate_limit_window`, no limit
+    // if not set
+    pub source_rate_limit: Option<usize>,
+    pub source_rate_limit_window: Duration,
+    // Callers are told apart by the client address in the headers set by a proxy instead of the
+    // address they connect from, only safe if nobody can reach the service without the proxy
+    pub trust_forwarded_headers: bool,
+
+    // How long the result of an alert with an `Idempotency-Key` is returned for repeats of it
+    pub idempotency_key_ttl: Duration,
+
+    // Named alert profiles, and the profile that is used for a schedule if the request names none
+    pub alert_profiles: HashMap<String, AlertProfile>,
+    pub schedule_profiles: HashMap<String, String>,
+
     pub opsgenie_config: OpsgenieConfig,
     pub twilio_config: TwilioConfig,
 
     pub slack_config: Option<SlackConfig>,
+
+    pub alert_history_config: AlertHistoryConfig,
+
+    pub heartbeat_config: HeartbeatConfig,
+
+    pub alert_mode: AlertMode,
+    // Url the service is reachable at from the outside, status urls of queued alerts are relative
+    // if not set
+    pub external_base_url: Option<Url>,
+
+    // Checks that are run against the upstream services before the server starts
+    pub startup_self_test: SelfTestMode,
+
+    // Alerts are suppressed while one of these is active
+    pub maintenance_windows: Vec<MaintenanceWindow>,
+    // Post suppressed alerts to the Slack webhook
+    pub notify_suppressed_alerts: bool,
+}
+
+/// Named alert settings, chosen per request with the `profile` query parameter or per schedule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlertProfile {
+    pub channel: AlertChannel,
+    // Alerts with this profile are not counted against the caller's rate limit
+    pub rate_limit_exempt: bool,
+}
+
+/// A planned time range in which nobody is paged, either for all schedules or for one schedule
+/// (matched against the id, name or group the alert was requested for).
+/// Start and end carry their UTC offset, so windows can be configured in local time.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MaintenanceWindow {
+    pub start: DateTime<FixedOffset>,
+    pub end: DateTime<FixedOffset>,
+    pub schedule: Option<String>,
+}
+
+impl MaintenanceWindow {
+    pub fn is_active(&self, now: DateTime<Utc>, schedule: &str) -> bool {
+        let schedule_matches = match &self.schedule {
+            Some(window_schedule) => window_schedule == schedule,
+            None => true,
+        };
+        self.start <= now && now < self.end && schedule_matches
+    }
+}
+
+/// Whether the startup self-test is skipped (e.g. in offline environments), only logs problems or
+/// aborts startup
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTestMode {
+    Off,
+    Warn,
+    Fail,
+}
+
+impl FromStr for SelfTestMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "off" => Ok(SelfTestMode::Off),
+            "warn" => Ok(SelfTestMode::Warn),
+            "fail" => Ok(SelfTestMode::Fail),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether `/alert` pages right away, or only stores the alert in a durable queue that a worker
+/// processes in the background
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AlertMode {
+    Inline,
+    Queued { directory: PathBuf },
+}
+
+/// Whether `/whosoncall` returns the on call information as is, or wrapped together with metadata
+/// about the response
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ResponseEnvelope {
+    Bare,
+    Enveloped,
+}
+
+/// Whether `/whosoncall` returns the first person on call as `username` and `phoneNumber` next to
+/// the full list, only the full list, or one number per person on call
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WhosOnCallView {
+    Full,
+    List,
+    Contacts,
+}
+
+impl FromStr for WhosOnCallView {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "full" => Ok(WhosOnCallView::Full),
+            "list" => Ok(WhosOnCallView::List),
+            "contacts" => Ok(WhosOnCallView::Contacts),
+            _ => Err(()),
+        }
+    }
+}
+
+impl FromStr for ResponseEnvelope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "bare" => Ok(ResponseEnvelope::Bare),
+            "enveloped" => Ok(ResponseEnvelope::Enveloped),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Response format of `/status`, to match what different monitoring systems expect
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusFormat {
+    // `{"health":"healthy"}`
+    Json,
+    // `{"status":"UP"}`
+    Actuator,
+    // Plain `OK` body
+    Text,
+}
+
+impl FromStr for StatusFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "json" => Ok(StatusFormat::Json),
+            "actuator" => Ok(StatusFormat::Actuator),
+            "text" => Ok(StatusFormat::Text),
+            _ => Err(()),
+        }
+    }
+}
+
+/// OpsGenie API versions whose responses we know how to parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpsgenieApiVersion {
+    V2,
+}
+
+impl OpsgenieApiVersion {
+    fn path_segment(&self) -> &'static str {
+        match self {
+            OpsgenieApiVersion::V2 => "v2",
+        }
+    }
+}
+
+impl FromStr for OpsgenieApiVersion {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "v2" => Ok(OpsgenieApiVersion::V2),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Appends the API version to the configured base url, so all requests can be joined relative to
+/// it. Trailing slashes and a version segment that is already present (as in the old default of
+/// `https://api.opsgenie.com/v2/`) are removed first.
+fn versioned_base_url(mut base_url: Url, api_version: OpsgenieApiVersion) -> Url {
+    let mut path = base_url.path().trim_end_matches('/');
+    if let Some((prefix, last_segment)) = path.rsplit_once('/') {
+        if OpsgenieApiVersion::from_str(last_segment).is_ok() {
+            tracing::warn!(
+                base_url = base_url.as_str(),
+                "OpsGenie base url contains an API version, please configure it separately via [{}]",
+                OPSGENIE_API_VERSION_ENVNAME
+            );
+            path = prefix;
+        }
+    }
+    let path = format!("{path}/{}/", api_version.path_segment());
+    base_url.set_path(&path);
+    base_url
+}
+
+// Relative urls are joined onto the last path segment, which would be dropped without the slash
+fn with_trailing_slash(mut url: Url) -> Url {
+    if !url.path().ends_with('/') {
+        let path = format!("{}/", url.path());
+        url.set_path(&path);
+    }
+    url
+}
+
+#[derive(Debug, Clone)]
+pub struct AlertHistoryConfig {
+    // Alerts are only persisted when this is set, otherwise they are kept in memory only
+    pub file: Option<PathBuf>,
+    pub max_file_bytes: u64,
+    // Number of rotated files to keep next to the current one
+    pub max_files: u64,
+    pub recent_entries: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct HeartbeatConfig {
+    // Warn if no `/alert` or `/whosoncall` request succeeded for this long, disabled if not set
+    pub window: Option<Duration>,
+    // Slack compatible incoming webhook that is notified as well
+    pub webhook_url: Option<Url>,
 }
 
 #[derive(Debug, Clone)]
@@ -120,14 +541,184 @@ pub struct SlackConfig {
 pub struct OpsgenieConfig {
     pub base_url: Url,
     pub credentials: SecretAuthHeader,
+    // Only on call recipients with one of these roles will be returned, an empty list disables
+    // filtering
+    pub role_filter: Vec<String>,
+    pub schedule_groups: HashMap<String, ScheduleGroup>,
+    // Keep people in the order OpsGenie returns them, otherwise they are sorted by username
+    pub preserve_recipient_order: bool,
+    // Teams that are on call are expanded into their members, at most `max_team_size` of them
+    pub expand_teams: bool,
+    pub max_team_size: usize,
+    // Fail the request instead of skipping the user if OpsGenie does not return their contacts
+    pub fail_on_unexpanded_contacts: bool,
+    // Sent as `identifierType` when looking up a user without it returned 404
+    pub user_identifier_type: UserIdentifierType,
+    // Additional query parameters that are passed on to the schedule and on call requests
+    pub extra_query_params: Vec<(String, String)>,
+    // Create an OpsGenie alert with this priority if nobody on call could be reached
+    pub alert_on_total_failure: bool,
+    pub alert_priority: String,
+    // Upper limit for the number of schedules returned by one request to `/schedules`
+    pub max_schedules_per_page: usize,
+    // Schedule metadata changes rarely, so it is cached for this long before being fetched again
+    pub metadata_refresh_interval: Duration,
+}
+
+/// How OpsGenie should interpret the user identifiers that are returned as on call recipients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UserIdentifierType {
+    Id,
+    Username,
+}
+
+impl UserIdentifierType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserIdentifierType::Id => "id",
+            UserIdentifierType::Username => "username",
+        }
+    }
+}
+
+impl FromStr for UserIdentifierType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "id" => Ok(UserIdentifierType::Id),
+            "username" => Ok(UserIdentifierType::Username),
+            _ => Err(()),
+        }
+    }
+}
+
+/// A primary and a backup schedule that can be requested together by the name of the group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScheduleGroup {
+    pub primary: String,
+    pub backup: String,
 }
 
 #[derive(Debug, Clone)]
 pub struct TwilioConfig {
     pub base_url: Url,
     pub credentials: SecretAuthHeader,
+    // Base url of the Twilio REST API, which is used to look up the numbers owned by the account
+    pub api_base_url: Url,
+    // Only needed for the startup self-test
+    pub account_sid: Option<String>,
     pub workflow_id: String,
     pub outgoing_number: String,
+    // Caller id that is passed to the Studio flow, so the person being called recognizes the call
+    pub caller_id: Option<String>,
+    // Call numbers that are shared by multiple on call persons once per person instead of once
+    pub treat_shared_numbers_individually: bool,
+    pub invalid_number_policy: InvalidNumberPolicy,
+    // Channel used for alerts that don't request one explicitly
+    pub default_channel: AlertChannel,
+    // Channel for alerts that are sent with an OpsGenie priority, keyed by P1 to P5
+    pub priority_channels: HashMap<String, AlertChannel>,
+    // Add machine readable error codes to failed calls in the alert result
+    pub include_error_codes: bool,
+    // Call numbers whose message Twilio rejects because of the number, off by default as every
+    // fallback is an additional call
+    pub sms_fallback_to_voice: bool,
+    // Information about the alerted schedule that is passed to the Twilio flow
+    pub schedule_parameters: Vec<ScheduleParameter>,
+    // Maximum number of calls and messages across all alerts within `paging_budget_window`,
+    // no limit if not set
+    pub paging_budget: Option<usize>,
+    pub paging_budget_window: Duration,
+}
+
+/// Information about the schedule an alert is for that can be passed to the Twilio flow, so it
+/// ends up in the Twilio logs and status callbacks
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ScheduleParameter {
+    ScheduleId,
+    ScheduleName,
+    ScheduleGroup,
+}
+
+impl ScheduleParameter {
+    /// Name of the parameter in the flow data
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScheduleParameter::ScheduleId => "scheduleId",
+            ScheduleParameter::ScheduleName => "scheduleName",
+            ScheduleParameter::ScheduleGroup => "scheduleGroup",
+        }
+    }
+}
+
+impl FromStr for ScheduleParameter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "scheduleid" => Ok(ScheduleParameter::ScheduleId),
+            "schedulename" => Ok(ScheduleParameter::ScheduleName),
+            "schedulegroup" => Ok(ScheduleParameter::ScheduleGroup),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How people are alerted, this is passed to the Twilio flow as the `channel` parameter
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertChannel {
+    Voice,
+    Sms,
+    Both,
+}
+
+impl AlertChannel {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AlertChannel::Voice => "voice",
+            AlertChannel::Sms => "sms",
+            AlertChannel::Both => "both",
+        }
+    }
+}
+
+impl FromStr for AlertChannel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "voice" => Ok(AlertChannel::Voice),
+            "sms" => Ok(AlertChannel::Sms),
+            "both" => Ok(AlertChannel::Both),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with phone numbers from OpsGenie that are not valid E.164 numbers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InvalidNumberPolicy {
+    /// Don't call the number, but still call all valid numbers
+    Skip,
+    /// Don't call anybody and return an error
+    FailRequest,
+    /// Pass the number to Twilio unchanged
+    DialAnyway,
+}
+
+impl FromStr for InvalidNumberPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "skip" => Ok(InvalidNumberPolicy::Skip),
+            "fail-request" => Ok(InvalidNumberPolicy::FailRequest),
+            "dial-anyway" => Ok(InvalidNumberPolicy::DialAnyway),
+            _ => Err(()),
+        }
+    }
 }
 
 impl Config {
@@ -161,6 +752,103 @@ impl Config {
         })?;
         tracing::debug!(bind_port, "Bind port set");
 
+        // Without any permits every lookup and call would wait forever
+        let max_concurrent_upstream_requests = ensure_non_zero(
+            extract_env_as_u64(
+                MAX_CONCURRENT_UPSTREAM_REQUESTS_ENVNAME,
+                MAX_CONCURRENT_UPSTREAM_REQUESTS_DEFAULT,
+            )?,
+            MAX_CONCURRENT_UPSTREAM_REQUESTS_ENVNAME,
+        )? as usize;
+        tracing::debug!(
+            max_concurrent_upstream_requests,
+            "Upstream concurrency limit set"
+        );
+
+        let readiness_grace_period = Duration::from_secs(extract_env_as_u64(
+            READINESS_GRACE_PERIOD_ENVNAME,
+            READINESS_GRACE_PERIOD_DEFAULT,
+        )?);
+
+        let whosoncall_log_level = extract_env_as_enum(
+            WHOSONCALL_LOG_LEVEL_ENVNAME,
+            REQUEST_LOG_LEVEL_DEFAULT,
+            "error, warn, info, debug, trace",
+        )?;
+        let alert_log_level = extract_env_as_enum(
+            ALERT_LOG_LEVEL_ENVNAME,
+            REQUEST_LOG_LEVEL_DEFAULT,
+            "error, warn, info, debug, trace",
+        )?;
+
+        let response_envelope = extract_env_as_enum(
+            RESPONSE_ENVELOPE_ENVNAME,
+            RESPONSE_ENVELOPE_DEFAULT,
+            "bare, enveloped",
+        )?;
+        let whosoncall_view = extract_env_as_enum(
+            WHOSONCALL_VIEW_ENVNAME,
+            WHOSONCALL_VIEW_DEFAULT,
+            "full, list, contacts",
+        )?;
+        let whosoncall_max_entries =
+            extract_env_as_optional_string(WHOSONCALL_MAX_ENTRIES_ENVNAME)?
+                .map(|max_entries| {
+                    usize::from_str(max_entries.trim()).context(ParseNumberSnafu {
+                        envname: WHOSONCALL_MAX_ENTRIES_ENVNAME,
+                    })
+                })
+                .transpose()?;
+        let status_format = extract_env_as_enum(
+            STATUS_FORMAT_ENVNAME,
+            STATUS_FORMAT_DEFAULT,
+            "json, actuator, text",
+        )?;
+
+        // A limit of 0 would refuse every alert, a window of 0 would never refuse any
+        let source_rate_limit = extract_env_as_optional_string(SOURCE_RATE_LIMIT_ENVNAME)?
+            .map(|limit| {
+                u64::from_str(limit.trim())
+                    .context(ParseNumberSnafu {
+                        envname: SOURCE_RATE_LIMIT_ENVNAME,
+                    })
+                    .and_then(|limit| ensure_non_zero(limit, SOURCE_RATE_LIMIT_ENVNAME))
+            })
+            .transpose()?
+            .map(|limit| limit as usize);
+        let source_rate_limit_window = Duration::from_secs(ensure_non_zero(
+            extract_env_as_u64(
+                SOURCE_RATE_LIMIT_WINDOW_ENVNAME,
+                SOURCE_RATE_LIMIT_WINDOW_DEFAULT,
+            )?,
+            SOURCE_RATE_LIMIT_WINDOW_ENVNAME,
+        )?);
+        let trust_forwarded_headers = extract_env_as_bool(
+            TRUST_FORWARDED_HEADERS_ENVNAME,
+            TRUST_FORWARDED_HEADERS_DEFAULT,
+        )?;
+        // The webhook server doesn't pass the address a request came from on to the handlers, so
+        // the proxy headers are the only way to tell callers apart. Without them the limit would
+        // silently never apply.
+        ensure!(
+            source_rate_limit.is_none() || trust_forwarded_headers,
+            SourceRateLimitWithoutForwardedHeadersSnafu {
+                envname: SOURCE_RATE_LIMIT_ENVNAME,
+                required: TRUST_FORWARDED_HEADERS_ENVNAME,
+            }
+        );
+
+        let idempotency_key_ttl = Duration::from_secs(extract_env_as_u64(
+            IDEMPOTENCY_KEY_TTL_ENVNAME,
+            IDEMPOTENCY_KEY_TTL_DEFAULT,
+        )?);
+
+        let alert_profiles = parse_alert_profiles(ALERT_PROFILES_ENVNAME)?;
+        tracing::debug!(?alert_profiles, "Alert profiles parsed");
+        let schedule_profiles =
+            parse_schedule_profiles(SCHEDULE_PROFILES_ENVNAME, &alert_profiles)?;
+        tracing::debug!(?schedule_profiles, "Schedule profiles parsed");
+
         let twilio_config = TwilioConfig::new()?;
         let opsgenie_config = OpsgenieConfig::new()?;
 
@@ -168,13 +856,74 @@ impl Config {
         // if we encounter an actual error, abort startup
         let slack_config = SlackConfig::new()?;
 
+        let alert_history_config = AlertHistoryConfig::new()?;
+
+        let heartbeat_config = HeartbeatConfig::new()?;
+
+        let alert_mode = parse_alert_mode()?;
+        tracing::debug!(?alert_mode, "Alert mode set");
+        let external_base_url = extract_env_as_optional_string(EXTERNAL_BASE_URL_ENVNAME)?
+            .map(|url| {
+                Url::parse(&url)
+                    .map(with_trailing_slash)
+                    .context(ConstructBaseUrlSnafu {
+                        service: "external base url",
+                    })
+            })
+            .transpose()?;
+
+        let maintenance_windows = parse_maintenance_windows(MAINTENANCE_WINDOWS_ENVNAME)?;
+        tracing::debug!(?maintenance_windows, "Maintenance windows parsed");
+        let notify_suppressed_alerts =
+            extract_env_as_bool(MAINTENANCE_NOTIFY_ENVNAME, MAINTENANCE_NOTIFY_DEFAULT)?;
+        ensure!(
+            !notify_suppressed_alerts || slack_config.is_some(),
+            MissingRequiredValueSnafu {
+                envname: SLACK_BASEURL_ENVNAME,
+            }
+        );
+
+        let startup_self_test = extract_env_as_enum(
+            STARTUP_SELF_TEST_ENVNAME,
+            STARTUP_SELF_TEST_DEFAULT,
+            "off, warn, fail",
+        )?;
+        // The outgoing number can only be looked up for a known account
+        ensure!(
+            startup_self_test == SelfTestMode::Off || twilio_config.account_sid.is_some(),
+            MissingRequiredValueSnafu {
+                envname: TWILIO_ACCOUNT_SID_ENVNAME,
+            }
+        );
+
         // Put it all together into a filled config object
         Ok(Config {
             bind_address: bind_address.into(),
             bind_port,
+            response_envelope,
+            whosoncall_view,
+            whosoncall_max_entries,
+            status_format,
+            whosoncall_log_level,
+            alert_log_level,
+            readiness_grace_period,
+            max_concurrent_upstream_requests,
+            source_rate_limit,
+            source_rate_limit_window,
+            trust_forwarded_headers,
+            idempotency_key_ttl,
+            alert_profiles,
+            schedule_profiles,
             opsgenie_config,
             twilio_config,
             slack_config,
+            alert_history_config,
+            heartbeat_config,
+            alert_mode,
+            external_base_url,
+            startup_self_test,
+            maintenance_windows,
+            notify_suppressed_alerts,
         })
     }
 }
@@ -194,14 +943,96 @@ impl OpsgenieConfig {
         .context(ConstructBaseUrlSnafu {
             service: "OpsGenie",
         })?;
+        let api_version = extract_env_as_enum(
+            OPSGENIE_API_VERSION_ENVNAME,
+            OPSGENIE_API_VERSION_DEFAULT,
+            "v2",
+        )?;
+        let base_url = versioned_base_url(base_url, api_version);
 
         tracing::debug!("OpsGenie base url parsed as : [{}]", base_url.to_string());
 
         let credentials = get_secret_header_from_env(OPSGENIE_TOKEN_ENVNAME)?;
 
+        let role_filter = extract_env_as_list(OPSGENIE_ROLE_FILTER_ENVNAME)?;
+        tracing::debug!(?role_filter, "OpsGenie role filter set");
+
+        let schedule_groups = parse_schedule_groups(OPSGENIE_SCHEDULE_GROUPS_ENVNAME)?;
+        tracing::debug!(?schedule_groups, "Schedule groups parsed");
+
+        let preserve_recipient_order = extract_env_as_bool(
+            OPSGENIE_PRESERVE_RECIPIENT_ORDER_ENVNAME,
+            OPSGENIE_PRESERVE_RECIPIENT_ORDER_DEFAULT,
+        )?;
+
+        let expand_teams =
+            extract_env_as_bool(OPSGENIE_EXPAND_TEAMS_ENVNAME, OPSGENIE_EXPAND_TEAMS_DEFAULT)?;
+        // A team that is on call would be expanded into nobody
+        let max_team_size = ensure_non_zero(
+            extract_env_as_u64(
+                OPSGENIE_MAX_TEAM_SIZE_ENVNAME,
+                OPSGENIE_MAX_TEAM_SIZE_DEFAULT,
+            )?,
+            OPSGENIE_MAX_TEAM_SIZE_ENVNAME,
+        )? as usize;
+
+        let fail_on_unexpanded_contacts = extract_env_as_bool(
+            OPSGENIE_FAIL_ON_UNEXPANDED_CONTACTS_ENVNAME,
+            OPSGENIE_FAIL_ON_UNEXPANDED_CONTACTS_DEFAULT,
+        )?;
+
+        let user_identifier_type = extract_env_as_enum(
+            OPSGENIE_USER_IDENTIFIER_TYPE_ENVNAME,
+            OPSGENIE_USER_IDENTIFIER_TYPE_DEFAULT,
+            "id, username",
+        )?;
+
+        let extra_query_params = parse_query_params(OPSGENIE_EXTRA_QUERY_PARAMS_ENVNAME)?;
+        tracing::debug!(?extra_query_params, "Extra OpsGenie query parameters set");
+
+        let alert_on_total_failure = extract_env_as_bool(
+            OPSGENIE_ALERT_ON_TOTAL_FAILURE_ENVNAME,
+            OPSGENIE_ALERT_ON_TOTAL_FAILURE_DEFAULT,
+        )?;
+        let alert_priority = extract_env_as_optional_string(OPSGENIE_ALERT_PRIORITY_ENVNAME)?
+            .unwrap_or(OPSGENIE_ALERT_PRIORITY_DEFAULT.to_string());
+        ensure!(
+            OPSGENIE_ALERT_PRIORITIES.contains(&alert_priority.as_str()),
+            InvalidAlertPrioritySnafu {
+                priority: &alert_priority,
+                envname: OPSGENIE_ALERT_PRIORITY_ENVNAME,
+            }
+        );
+
+        let max_schedules_per_page = extract_env_as_u64(
+            OPSGENIE_MAX_SCHEDULES_PER_PAGE_ENVNAME,
+            OPSGENIE_MAX_SCHEDULES_PER_PAGE_DEFAULT,
+        )? as usize;
+
+        let metadata_refresh_interval = Duration::from_secs(extract_env_as_u64(
+            OPSGENIE_METADATA_REFRESH_ENVNAME,
+            OPSGENIE_METADATA_REFRESH_DEFAULT,
+        )?);
+        tracing::debug!(
+            ?metadata_refresh_interval,
+            "Schedule metadata refresh interval set"
+        );
+
         Ok(OpsgenieConfig {
             base_url,
             credentials,
+            role_filter,
+            schedule_groups,
+            preserve_recipient_order,
+            expand_teams,
+            max_team_size,
+            fail_on_unexpanded_contacts,
+            user_identifier_type,
+            extra_query_params,
+            alert_on_total_failure,
+            alert_priority,
+            max_schedules_per_page,
+            metadata_refresh_interval,
         })
     }
 }
@@ -224,6 +1055,19 @@ impl TwilioConfig {
 
         let credentials = get_secret_header_from_env(TWILIO_TOKEN_ENVNAME)?;
 
+        let api_base_url = Url::parse(
+            env::var_os(TWILIO_API_BASEURL_ENVNAME)
+                .unwrap_or(OsString::from(TWILIO_API_BASEURL_DEFAULT))
+                .to_str()
+                .context(ConvertOsStringSnafu {
+                    envname: TWILIO_API_BASEURL_ENVNAME,
+                })?,
+        )
+        .context(ConstructBaseUrlSnafu {
+            service: "Twilio API",
+        })?;
+        let account_sid = extract_env_as_optional_string(TWILIO_ACCOUNT_SID_ENVNAME)?;
+
         let workflow_id = env::var_os(TWILIO_WORKFLOW_ENVNAME)
             .context(MissingRequiredValueSnafu {
                 envname: TWILIO_WORKFLOW_ENVNAME,
@@ -244,11 +1088,93 @@ impl TwilioConfig {
             })?
             .to_string();
 
+        let treat_shared_numbers_individually = extract_env_as_bool(
+            TWILIO_SHARED_NUMBERS_INDIVIDUALLY_ENVNAME,
+            TWILIO_SHARED_NUMBERS_INDIVIDUALLY_DEFAULT,
+        )?;
+
+        let caller_id = extract_env_as_optional_string(TWILIO_CALLER_ID_ENVNAME)?;
+        if let Some(caller_id) = &caller_id {
+            ensure!(
+                is_e164(caller_id),
+                InvalidCallerIdSnafu {
+                    caller_id,
+                    envname: TWILIO_CALLER_ID_ENVNAME,
+                }
+            );
+        }
+
+        let invalid_number_policy = extract_env_as_enum(
+            TWILIO_INVALID_NUMBER_POLICY_ENVNAME,
+            TWILIO_INVALID_NUMBER_POLICY_DEFAULT,
+            "skip, fail-request, dial-anyway",
+        )?;
+
+        let default_channel = extract_env_as_enum(
+            TWILIO_ALERT_CHANNEL_ENVNAME,
+            TWILIO_ALERT_CHANNEL_DEFAULT,
+            "voice, sms, both",
+        )?;
+
+        let priority_channels = parse_priority_channels(TWILIO_PRIORITY_CHANNELS_ENVNAME)?;
+        tracing::debug!(?priority_channels, "Priority channels parsed");
+
+        let include_error_codes =
+            extract_env_as_bool(TWILIO_ERROR_CODES_ENVNAME, TWILIO_ERROR_CODES_DEFAULT)?;
+        let sms_fallback_to_voice = extract_env_as_bool(
+            TWILIO_SMS_FALLBACK_TO_VOICE_ENVNAME,
+            TWILIO_SMS_FALLBACK_TO_VOICE_DEFAULT,
+        )?;
+
+        let schedule_parameters = extract_env_as_list(TWILIO_SCHEDULE_PARAMETERS_ENVNAME)?
+            .into_iter()
+            .map(|parameter| {
+                ScheduleParameter::from_str(&parameter)
+                    .ok()
+                    .context(InvalidValueSnafu {
+                        value: &parameter,
+                        envname: TWILIO_SCHEDULE_PARAMETERS_ENVNAME,
+                        expected: "scheduleId, scheduleName, scheduleGroup",
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        // A budget of 0 would refuse every alert, a window of 0 would never refuse any
+        let paging_budget = extract_env_as_optional_string(TWILIO_PAGING_BUDGET_ENVNAME)?
+            .map(|budget| {
+                u64::from_str(budget.trim())
+                    .context(ParseNumberSnafu {
+                        envname: TWILIO_PAGING_BUDGET_ENVNAME,
+                    })
+                    .and_then(|budget| ensure_non_zero(budget, TWILIO_PAGING_BUDGET_ENVNAME))
+            })
+            .transpose()?
+            .map(|budget| budget as usize);
+        let paging_budget_window = Duration::from_secs(ensure_non_zero(
+            extract_env_as_u64(
+                TWILIO_PAGING_BUDGET_WINDOW_ENVNAME,
+                TWILIO_PAGING_BUDGET_WINDOW_DEFAULT,
+            )?,
+            TWILIO_PAGING_BUDGET_WINDOW_ENVNAME,
+        )?);
+
         Ok(TwilioConfig {
             base_url,
             credentials,
+            api_base_url,
+            account_sid,
             workflow_id,
             outgoing_number,
+            caller_id,
+            treat_shared_numbers_individually,
+            invalid_number_policy,
+            default_channel,
+            priority_channels,
+            include_error_codes,
+            sms_fallback_to_voice,
+            schedule_parameters,
+            paging_budget,
+            paging_budget_window,
         })
     }
 }
@@ -279,6 +1205,234 @@ impl SlackConfig {
     }
 }
 
+// Schedule groups are configured as a comma separated list of `group=primary:backup` entries
+fn parse_schedule_groups(envname: &str) -> Result<HashMap<String, ScheduleGroup>, ConfigError> {
+    let mut schedule_groups = HashMap::new();
+    for entry in extract_env_as_list(envname)? {
+        let (group, schedules) = entry.split_once('=').context(ParseScheduleGroupSnafu {
+            entry: &entry,
+            envname,
+        })?;
+        let (primary, backup) = schedules.split_once(':').context(ParseScheduleGroupSnafu {
+            entry: &entry,
+            envname,
+        })?;
+        schedule_groups.insert(
+            group.trim().to_string(),
+            ScheduleGroup {
+                primary: primary.trim().to_string(),
+                backup: backup.trim().to_string(),
+            },
+        );
+    }
+    Ok(schedule_groups)
+}
+
+// Priority channels are configured as a comma separated list of `priority=channel` entries, that
+// override the built-in mapping for these priorities
+fn parse_priority_channels(envname: &str) -> Result<HashMap<String, AlertChannel>, ConfigError> {
+    let mut priority_channels: HashMap<String, AlertChannel> = TWILIO_PRIORITY_CHANNELS_DEFAULT
+        .iter()
+        .map(|(priority, channel)| (priority.to_string(), *channel))
+        .collect();
+    for entry in extract_env_as_list(envname)? {
+        let (priority, channel) = entry.split_once('=').context(ParsePriorityChannelSnafu {
+            entry: &entry,
+            envname,
+        })?;
+        let priority = priority.trim().to_uppercase();
+        ensure!(
+            OPSGENIE_ALERT_PRIORITIES.contains(&priority.as_str()),
+            InvalidAlertPrioritySnafu { priority, envname }
+        );
+        let channel = AlertChannel::from_str(channel.trim())
+            .ok()
+            .context(InvalidValueSnafu {
+                value: channel.trim(),
+                envname,
+                expected: "voice, sms, both",
+            })?;
+        priority_channels.insert(priority, channel);
+    }
+    Ok(priority_channels)
+}
+
+// Alert profiles are configured as a comma separated list of `name=channel` entries, appending
+// `:unlimited` exempts alerts with the profile from the per caller rate limit
+fn parse_alert_profiles(envname: &str) -> Result<HashMap<String, AlertProfile>, ConfigError> {
+    extract_env_as_list(envname)?
+        .iter()
+        .map(|entry| parse_alert_profile(entry).context(ParseAlertProfileSnafu { entry, envname }))
+        .collect()
+}
+
+fn parse_alert_profile(entry: &str) -> Option<(String, AlertProfile)> {
+    let (name, settings) = entry.split_once('=')?;
+    let (channel, rate_limit_exempt) = match settings.split_once(':') {
+        Some((channel, flag)) if flag.trim() == ALERT_PROFILE_UNLIMITED => (channel, true),
+        Some(_) => return None,
+        None => (settings, false),
+    };
+    let channel = AlertChannel::from_str(channel.trim()).ok()?;
+    Some((
+        name.trim().to_string(),
+        AlertProfile {
+            channel,
+            rate_limit_exempt,
+        },
+    ))
+}
+
+// Schedule profiles are configured as a comma separated list of `schedule=profile` entries, the
+// schedule is matched against the id, name or group the alert was requested for
+fn parse_schedule_profiles(
+    envname: &str,
+    alert_profiles: &HashMap<String, AlertProfile>,
+) -> Result<HashMap<String, String>, ConfigError> {
+    let mut schedule_profiles = HashMap::new();
+    for entry in extract_env_as_list(envname)? {
+        let (schedule, profile) = entry.split_once('=').context(ParseScheduleProfileSnafu {
+            entry: &entry,
+            envname,
+        })?;
+        let profile = profile.trim();
+        ensure!(
+            alert_profiles.contains_key(profile),
+            UnknownAlertProfileSnafu { profile, envname }
+        );
+        schedule_profiles.insert(schedule.trim().to_string(), profile.to_string());
+    }
+    Ok(schedule_profiles)
+}
+
+// Maintenance windows are configured as a comma separated list of `start/end` entries, that can
+// be limited to one schedule with `start/end@schedule`
+fn parse_maintenance_windows(envname: &str) -> Result<Vec<MaintenanceWindow>, ConfigError> {
+    extract_env_as_list(envname)?
+        .iter()
+        .map(|entry| {
+            parse_maintenance_window(entry).context(ParseMaintenanceWindowSnafu { entry, envname })
+        })
+        .collect()
+}
+
+fn parse_maintenance_window(entry: &str) -> Option<MaintenanceWindow> {
+    let (range, schedule) = match entry.split_once('@') {
+        Some((range, schedule)) => (range, Some(schedule.trim().to_string())),
+        None => (entry, None),
+    };
+    let (start, end) = range.split_once('/')?;
+    let start = DateTime::parse_from_rfc3339(start.trim()).ok()?;
+    let end = DateTime::parse_from_rfc3339(end.trim()).ok()?;
+    (start < end).then_some(MaintenanceWindow {
+        start,
+        end,
+        schedule,
+    })
+}
+
+impl AlertHistoryConfig {
+    pub fn new() -> Result<Self, ConfigError> {
+        let file = extract_env_as_optional_string(ALERT_HISTORY_FILE_ENVNAME)?.map(PathBuf::from);
+        if file.is_none() {
+            tracing::info!(
+                "[{ALERT_HISTORY_FILE_ENVNAME}] not set, alert history will not be persisted"
+            );
+        }
+
+        Ok(AlertHistoryConfig {
+            file,
+            max_file_bytes: extract_env_as_u64(
+                ALERT_HISTORY_MAX_BYTES_ENVNAME,
+                ALERT_HISTORY_MAX_BYTES_DEFAULT,
+            )?,
+            max_files: extract_env_as_u64(
+                ALERT_HISTORY_MAX_FILES_ENVNAME,
+                ALERT_HISTORY_MAX_FILES_DEFAULT,
+            )?,
+            recent_entries: extract_env_as_u64(
+                ALERT_HISTORY_RECENT_ENTRIES_ENVNAME,
+                ALERT_HISTORY_RECENT_ENTRIES_DEFAULT,
+            )? as usize,
+        })
+    }
+}
+
+impl HeartbeatConfig {
+    pub fn new() -> Result<Self, ConfigError> {
+        let window = extract_env_as_optional_string(HEARTBEAT_WINDOW_ENVNAME)?
+            .map(|window| {
+                u64::from_str(window.trim()).context(ParseNumberSnafu {
+                    envname: HEARTBEAT_WINDOW_ENVNAME,
+                })
+            })
+            .transpose()?
+            .map(Duration::from_secs);
+        let webhook_url = extract_env_as_optional_string(HEARTBEAT_WEBHOOK_ENVNAME)?
+            .map(|url| {
+                Url::parse(&url).context(ConstructBaseUrlSnafu {
+                    service: "heartbeat webhook",
+                })
+            })
+            .transpose()?;
+        if window.is_none() {
+            tracing::info!(
+                "[{HEARTBEAT_WINDOW_ENVNAME}] not set, heartbeat monitoring is disabled"
+            );
+        }
+
+        Ok(HeartbeatConfig {
+            window,
+            webhook_url,
+        })
+    }
+}
+
+fn parse_alert_mode() -> Result<AlertMode, ConfigError> {
+    match extract_env_as_optional_string(ALERT_MODE_ENVNAME)?
+        .map(|mode| mode.trim().to_lowercase())
+        .as_deref()
+    {
+        None | Some("inline") => Ok(AlertMode::Inline),
+        Some("queued") => Ok(AlertMode::Queued {
+            directory: extract_env_as_optional_string(ALERT_QUEUE_DIR_ENVNAME)?
+                .map(PathBuf::from)
+                .context(MissingAlertQueueDirSnafu {
+                    envname: ALERT_QUEUE_DIR_ENVNAME,
+                })?,
+        }),
+        Some(mode) => InvalidValueSnafu {
+            value: mode,
+            envname: ALERT_MODE_ENVNAME,
+            expected: "inline, queued",
+        }
+        .fail(),
+    }
+}
+
+// Extra query parameters are configured as a comma separated list of `key=value` entries
+fn parse_query_params(envname: &str) -> Result<Vec<(String, String)>, ConfigError> {
+    let mut query_params = Vec::new();
+    for entry in extract_env_as_list(envname)? {
+        let (key, value) = entry.split_once('=').context(ParseQueryParameterSnafu {
+            entry: &entry,
+            envname,
+        })?;
+        let key = key.trim();
+        ensure!(
+            !OPSGENIE_RESERVED_QUERY_PARAMS
+                .iter()
+                .any(|reserved| reserved.eq_ignore_ascii_case(key)),
+            ReservedQueryParameterSnafu {
+                parameter: key,
+                envname,
+            }
+        );
+        query_params.push((key.to_string(), value.trim().to_string()));
+    }
+    Ok(query_params)
+}
+
 fn get_secret_header_from_env(envname: &str) -> Result<SecretAuthHeader, ConfigError> {
     Ok(SecretAuthHeader::new(AuthHeader(
         HeaderValue::from_str(
@@ -310,3 +1464,161 @@ fn extract_env_as_bool(envname: impl AsRef<str>, default: bool) -> Result<bool,
         }),
     }
 }
+
+fn extract_env_as_u64(envname: impl AsRef<str>, default: u64) -> Result<u64, ConfigError> {
+    match env::var(envname.as_ref()) {
+        Ok(value) => Ok(u64::from_str(&value).context(ParseNumberSnafu {
+            envname: envname.as_ref(),
+        })?),
+        Err(e) if e == VarError::NotPresent => Ok(default),
+        Err(e) => Err(e).context(ConvertEnvStringSnafu {
+            envname: envname.as_ref(),
+        }),
+    }
+}
+
+fn ensure_non_zero(value: u64, envname: &str) -> Result<u64, ConfigError> {
+    ensure!(value > 0, ZeroValueSnafu { envname });
+    Ok(value)
+}
+
+fn extract_env_as_enum<T: FromStr>(
+    envname: impl AsRef<str>,
+    default: T,
+    expected: &str,
+) -> Result<T, ConfigError> {
+    match env::var(envname.as_ref()) {
+        Ok(value) => T::from_str(value.trim()).ok().context(InvalidValueSnafu {
+            value: &value,
+            envname: envname.as_ref(),
+            expected,
+        }),
+        Err(e) if e == VarError::NotPresent => Ok(default),
+        Err(e) => Err(e).context(ConvertEnvStringSnafu {
+            envname: envname.as_ref(),
+        }),
+    }
+}
+
+fn extract_env_as_optional_string(envname: impl AsRef<str>) -> Result<Option<String>, ConfigError> {
+    match env::var(envname.as_ref()) {
+        Ok(value) => Ok(Some(value)),
+        Err(e) if e == VarError::NotPresent => Ok(None),
+        Err(e) => Err(e).context(ConvertEnvStringSnafu {
+            envname: envname.as_ref(),
+        }),
+    }
+}
+
+fn extract_env_as_list(envname: impl AsRef<str>) -> Result<Vec<String>, ConfigError> {
+    match env::var(envname.as_ref()) {
+        Ok(value) => Ok(value
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(String::from)
+            .collect()),
+        Err(e) if e == VarError::NotPresent => Ok(Vec::new()),
+        Err(e) => Err(e).context(ConvertEnvStringSnafu {
+            envname: envname.as_ref(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        ensure_non_zero, parse_alert_profile, parse_maintenance_window, versioned_base_url,
+        with_trailing_slash, AlertChannel, AlertProfile, ConfigError, OpsgenieApiVersion,
+        MAX_CONCURRENT_UPSTREAM_REQUESTS_ENVNAME,
+    };
+    use chrono::{DateTime, Utc};
+    use rstest::rstest;
+    use url::Url;
+
+    #[rstest]
+    #[case("https://api.opsgenie.com", "https://api.opsgenie.com/v2/")]
+    #[case("https://api.opsgenie.com/", "https://api.opsgenie.com/v2/")]
+    #[case("https://api.opsgenie.com/v2", "https://api.opsgenie.com/v2/")]
+    #[case("https://api.opsgenie.com/v2/", "https://api.opsgenie.com/v2/")]
+    #[case(
+        "https://proxy.example.com/opsgenie/",
+        "https://proxy.example.com/opsgenie/v2/"
+    )]
+    fn test_versioned_base_url(#[case] base_url: &str, #[case] expected: &str) {
+        assert_eq!(
+            versioned_base_url(Url::parse(base_url).unwrap(), OpsgenieApiVersion::V2).as_str(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::inside("2026-10-20T21:00:00Z", "ops", true)]
+    #[case::start_is_inclusive("2026-10-20T20:00:00Z", "ops", true)]
+    #[case::end_is_exclusive("2026-10-21T00:00:00Z", "ops", false)]
+    #[case::before("2026-10-20T19:59:59Z", "ops", false)]
+    #[case::other_schedule("2026-10-20T21:00:00Z", "dev", false)]
+    fn test_maintenance_window(#[case] now: &str, #[case] schedule: &str, #[case] expected: bool) {
+        // 22:00 to 02:00 local time at UTC+2
+        let window =
+            parse_maintenance_window("2026-10-20T22:00:00+02:00/2026-10-21T02:00:00+02:00@ops")
+                .unwrap();
+        let now = DateTime::parse_from_rfc3339(now)
+            .unwrap()
+            .with_timezone(&Utc);
+        assert_eq!(window.is_active(now, schedule), expected);
+    }
+
+    #[rstest]
+    #[case::missing_end("2026-10-20T22:00:00Z")]
+    #[case::no_offset("2026-10-20T22:00:00/2026-10-21T02:00:00")]
+    #[case::end_before_start("2026-10-21T02:00:00Z/2026-10-20T22:00:00Z")]
+    fn test_invalid_maintenance_window(#[case] entry: &str) {
+        assert_eq!(parse_maintenance_window(entry), None);
+    }
+
+    #[rstest]
+    #[case("https://example.com", "https://example.com/")]
+    #[case("https://example.com/oncall", "https://example.com/oncall/")]
+    #[case("https://example.com/oncall/", "https://example.com/oncall/")]
+    fn test_with_trailing_slash(#[case] url: &str, #[case] expected: &str) {
+        assert_eq!(
+            with_trailing_slash(Url::parse(url).unwrap()).as_str(),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::channel("routine=sms", Some(("routine", AlertChannel::Sms, false)))]
+    #[case::unlimited(" critical = Voice:unlimited", Some(("critical", AlertChannel::Voice, true)))]
+    #[case::unknown_channel("critical=fax", None)]
+    #[case::unknown_flag("critical=voice:fast", None)]
+    #[case::missing_channel("critical", None)]
+    fn test_parse_alert_profile(
+        #[case] entry: &str,
+        #[case] expected: Option<(&str, AlertChannel, bool)>,
+    ) {
+        let expected = expected.map(|(name, channel, rate_limit_exempt)| {
+            (
+                name.to_string(),
+                AlertProfile {
+                    channel,
+                    rate_limit_exempt,
+                },
+            )
+        });
+        assert_eq!(parse_alert_profile(entry), expected);
+    }
+
+    #[test]
+    fn test_max_concurrent_upstream_requests_rejects_zero() {
+        assert!(matches!(
+            ensure_non_zero(0, MAX_CONCURRENT_UPSTREAM_REQUESTS_ENVNAME),
+            Err(ConfigError::ZeroValue { .. })
+        ));
+        assert_eq!(
+            ensure_non_zero(1, MAX_CONCURRENT_UPSTREAM_REQUESTS_ENVNAME).unwrap(),
+            1
+        );
+    }
+}
@@ -0,0 +1,115 @@
+use crate::config::HeartbeatConfig;
+use chrono::{DateTime, Utc};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Upper bound for how long it takes the watchdog to notice a missed heartbeat
+static MAX_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Dead man's switch for the service itself: if no `/alert` or `/whosoncall` request succeeded
+/// within the configured window, the service is up but apparently not used (or not usable), which
+/// is logged and reported to the configured webhook once per silent period.
+#[derive(Debug, Clone)]
+pub struct Heartbeat {
+    config: HeartbeatConfig,
+    state: Arc<Mutex<HeartbeatState>>,
+}
+
+#[derive(Debug)]
+struct HeartbeatState {
+    // Starts out as the startup time, so a service that is never used gets reported as well
+    last_success: Instant,
+    last_success_at: Option<DateTime<Utc>>,
+    notified: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct HeartbeatStatus {
+    pub last_success: Option<DateTime<Utc>>,
+    // Not set if heartbeat monitoring is disabled
+    pub window_seconds: Option<u64>,
+    pub stale: bool,
+}
+
+impl Heartbeat {
+    pub fn new(config: HeartbeatConfig) -> Self {
+        Heartbeat {
+            config,
+            state: Arc::new(Mutex::new(HeartbeatState {
+                last_success: Instant::now(),
+                last_success_at: None,
+                notified: false,
+            })),
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().expect("heartbeat lock is never poisoned");
+        if state.notified {
+            tracing::info!("Requests are succeeding again, heartbeat recovered");
+        }
+        state.last_success = Instant::now();
+        state.last_success_at = Some(Utc::now());
+        state.notified = false;
+    }
+
+    pub fn status(&self) -> HeartbeatStatus {
+        let state = self.state.lock().expect("heartbeat lock is never poisoned");
+        HeartbeatStatus {
+            last_success: state.last_success_at,
+            window_seconds: self.config.window.map(|window| window.as_secs()),
+            stale: self
+                .config
+                .window
+                .is_some_and(|window| state.last_success.elapsed() > window),
+        }
+    }
+
+    /// Periodically checks for a missed heartbeat, returns right away if monitoring is disabled
+    pub async fn watch(self, http: Client) {
+        let Some(window) = self.config.window else {
+            return;
+        };
+        let mut interval =
+            tokio::time::interval(window.clamp(Duration::from_secs(1), MAX_CHECK_INTERVAL));
+        loop {
+            interval.tick().await;
+            let should_notify = {
+                let mut state = self.state.lock().expect("heartbeat lock is never poisoned");
+                let missed = state.last_success.elapsed() > window && !state.notified;
+                state.notified |= missed;
+                missed
+            };
+            if should_notify {
+                self.notify(&http, window).await;
+            }
+        }
+    }
+
+    async fn notify(&self, http: &Client, window: Duration) {
+        let message = format!(
+            "{} has not handled a successful /alert or /whosoncall request in the last {} seconds, it may be silently broken",
+            crate::APP_NAME,
+            window.as_secs()
+        );
+        tracing::warn!(last_success = ?self.status().last_success, "{message}");
+
+        if let Some(webhook_url) = &self.config.webhook_url {
+            let result = http
+                .post(webhook_url.clone())
+                .json(&serde_json::json!({ "text": message }))
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(error) = result {
+                tracing::error!(
+                    error = &error as &dyn std::error::Error,
+                    "Failed to send missed heartbeat notification"
+                );
+            }
+        }
+    }
+}
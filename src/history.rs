@@ -0,0 +1,189 @@
+use crate::config::AlertHistoryConfig;
+use crate::history::error::{ReadFileSnafu, RotateFileSnafu, SerializeRecordSnafu, WriteFileSnafu};
+use crate::twilio::AlertResult;
+use crate::Schedule;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub(crate) enum Error {
+    #[snafu(display("failed to serialize alert record: \n{source}"))]
+    SerializeRecord { source: serde_json::Error },
+    #[snafu(display("failed to write alert history file [{}]: \n{source}", path.display()))]
+    WriteFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to rotate alert history file [{}]: \n{source}", path.display()))]
+    RotateFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to read alert history file [{}]: \n{source}", path.display()))]
+    ReadFile {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertRecord {
+    pub timestamp: DateTime<Utc>,
+    pub schedule: Schedule,
+    pub result: AlertResult,
+}
+
+/// Keeps the most recent alerts in memory and, if a file is configured, appends every alert to a
+/// JSONL file so the history survives restarts.
+/// Failing to persist a record is logged but never fails the alert itself, the page has already
+/// gone out at that point.
+#[derive(Debug, Clone)]
+pub struct AlertHistory {
+    config: AlertHistoryConfig,
+    recent: Arc<Mutex<VecDeque<AlertRecord>>>,
+}
+
+impl AlertHistory {
+    /// Creates the history and repopulates the recent alerts from the history file, if one is
+    /// configured
+    pub async fn load(config: AlertHistoryConfig) -> Self {
+        let mut recent = VecDeque::new();
+        if let Some(path) = &config.file {
+            match read_tail(path, config.recent_entries).await {
+                Ok(records) => {
+                    tracing::info!(
+                        records = records.len(),
+                        ?path,
+                        "Reloaded recent alerts from history file"
+                    );
+                    recent = records;
+                }
+                Err(error) => tracing::warn!(
+                    error = &error as &dyn std::error::Error,
+                    "Failed to reload recent alerts from history file, starting with an empty history"
+                ),
+            }
+        }
+        AlertHistory {
+            config,
+            recent: Arc::new(Mutex::new(recent)),
+        }
+    }
+
+    pub async fn record(&self, record: AlertRecord) {
+        // Holding the lock while writing keeps the file in the same order as the in-memory list
+        let mut recent = self.recent.lock().await;
+        if let Some(path) = &self.config.file {
+            if let Err(error) = append_record(
+                path,
+                &record,
+                self.config.max_file_bytes,
+                self.config.max_files,
+            )
+            .await
+            {
+                tracing::warn!(
+                    error = &error as &dyn std::error::Error,
+                    "Failed to persist alert record, it will only be kept in memory"
+                );
+            }
+        }
+        recent.push_back(record);
+        while recent.len() > self.config.recent_entries {
+            recent.pop_front();
+        }
+    }
+
+    pub async fn recent(&self) -> Vec<AlertRecord> {
+        self.recent.lock().await.iter().cloned().collect()
+    }
+}
+
+async fn append_record(
+    path: &Path,
+    record: &AlertRecord,
+    max_file_bytes: u64,
+    max_files: u64,
+) -> Result<(), Error> {
+    let mut line = serde_json::to_string(record).context(SerializeRecordSnafu)?;
+    line.push('\n');
+
+    // Rotate before writing if this record would push the file over the size limit
+    if let Ok(metadata) = tokio::fs::metadata(path).await {
+        if metadata.len() > 0 && metadata.len() + line.len() as u64 > max_file_bytes {
+            tracing::debug!(?path, "Rotating alert history file");
+            rotate(path, max_files).await?;
+        }
+    }
+
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .context(WriteFileSnafu { path })?;
+    file.write_all(line.as_bytes())
+        .await
+        .context(WriteFileSnafu { path })?;
+    file.flush().await.context(WriteFileSnafu { path })
+}
+
+/// Shifts `history.jsonl.1` to `history.jsonl.2` and so on, dropping everything beyond
+/// `max_files`, and moves the current file to `history.jsonl.1`
+async fn rotate(path: &Path, max_files: u64) -> Result<(), Error> {
+    if max_files == 0 {
+        return tokio::fs::remove_file(path)
+            .await
+            .context(RotateFileSnafu { path });
+    }
+    for index in (1..max_files).rev() {
+        let from = rotated_path(path, index);
+        if tokio::fs::try_exists(&from).await.unwrap_or(false) {
+            tokio::fs::rename(&from, rotated_path(path, index + 1))
+                .await
+                .context(RotateFileSnafu { path: &from })?;
+        }
+    }
+    tokio::fs::rename(path, rotated_path(path, 1))
+        .await
+        .context(RotateFileSnafu { path })
+}
+
+fn rotated_path(path: &Path, index: u64) -> PathBuf {
+    let mut file_name = path.as_os_str().to_owned();
+    file_name.push(format!(".{index}"));
+    PathBuf::from(file_name)
+}
+
+async fn read_tail(path: &Path, count: usize) -> Result<VecDeque<AlertRecord>, Error> {
+    let content = match tokio::fs::read_to_string(path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(VecDeque::new()),
+        Err(e) => return Err(e).context(ReadFileSnafu { path }),
+    };
+
+    let mut records = VecDeque::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty()) {
+        match serde_json::from_str::<AlertRecord>(line) {
+            Ok(record) => {
+                records.push_back(record);
+                if records.len() > count {
+                    records.pop_front();
+                }
+            }
+            Err(error) => tracing::warn!(
+                error = &error as &dyn std::error::Error,
+                "Skipping unreadable line in alert history file"
+            ),
+        }
+    }
+    Ok(records)
+}
@@ -1,43 +1,85 @@
 mod config;
+mod heartbeat;
+mod history;
 mod http_error;
 mod opsgenie;
+mod queue;
 mod twilio;
 mod util;
 
-use crate::config::{enable_log_exporter, enable_trace_exporter, Config, ConfigError};
-use crate::opsgenie::{get_oncall_number, UserPhoneNumber};
-use crate::twilio::{alert, AlertResult};
+use crate::config::{
+    enable_log_exporter, enable_trace_exporter, AlertChannel, AlertMode, AlertProfile, Config,
+    ConfigError, ResponseEnvelope, SelfTestMode, StatusFormat, WhosOnCallView,
+};
+use crate::heartbeat::{Heartbeat, HeartbeatStatus};
+use crate::history::{AlertHistory, AlertRecord};
+use crate::opsgenie::{
+    check_connectivity, contact_method_metrics, create_paging_budget_alert,
+    create_total_failure_alert, get_oncall_number, get_schedule_metadata, list_schedules,
+    ContactStatus, ScheduleList, ScheduleMetadata, ScheduleMetadataCache, ScheduleTier,
+    UserPhoneNumber,
+};
+use crate::queue::{AlertQueue, AlertStatus, DialProgress};
+use crate::twilio::{
+    alert, check_outgoing_number, plan_alert, AlertPlan, AlertResult, DialNumberResult,
+    IdempotentAlerts, OverallResult, PagingBudget, PlannedCall,
+};
+use crate::util::{event_at_level, RateLimiter};
 use crate::StartupError::{InitializeTelemetry, ParseConfig};
 use axum::body::Bytes;
-use axum::extract::Query;
-use axum::http::HeaderMap;
+use axum::extract::{ConnectInfo, Path, Query};
+use axum::http::header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE, LOCATION};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
 use axum::routing::get;
 use axum::{extract::State, Json, Router};
+use chrono::{DateTime, Utc};
 use futures::{future, pin_mut, FutureExt};
 use reqwest::{ClientBuilder, Url};
+use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
 use snafu::{OptionExt, ResultExt, Snafu};
 use stackable_operator::kube::config::InferConfigError;
 use stackable_operator::logging::TracingTarget;
 use stackable_telemetry::{AxumTraceLayer, Tracing};
+use std::collections::HashMap;
 use std::env;
 use std::env::var_os;
 use std::ffi::OsString;
 use std::fmt::{Debug, Display, Formatter};
+use std::net::SocketAddr;
 use std::process::{ExitCode, Termination};
-use std::str::ParseBoolError;
-use std::time::Duration;
+use std::str::{FromStr, ParseBoolError};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::TcpListener;
+use tokio::sync::Semaphore;
 use tracing::field::{Field, Visit};
 use tracing::level_filters::LevelFilter;
 use tracing::{instrument, Value};
 
 pub const APP_NAME: &str = "who-you-gonna-call";
 
+// Queued alerts are picked up right away, this is only the fallback if a wakeup was missed
+static ALERT_QUEUE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
 #[derive(Debug, Clone)]
 struct AppState {
     http: reqwest::Client,
     config: Config,
+    schedule_metadata: ScheduleMetadataCache,
+    alert_history: AlertHistory,
+    heartbeat: Heartbeat,
+    paging_budget: PagingBudget,
+    // Results of alerts that carried an `Idempotency-Key`, replayed when the key is seen again
+    idempotent_alerts: IdempotentAlerts,
+    // Only set if `/alert` requests are limited per caller
+    source_rate_limiter: Option<RateLimiter<String>>,
+    // Only set if alerts are queued instead of handled inline
+    alert_queue: Option<AlertQueue>,
+    // Bounds the number of requests to OpsGenie and Twilio that are in flight at the same time
+    upstream_permits: Arc<Semaphore>,
+    started_at: Instant,
 }
 
 #[derive(Snafu, Debug)]
@@ -57,6 +99,12 @@ enum StartupError {
     #[snafu(display("failed to construct http client: \n{source}"))]
     ConstructHttpClient { source: reqwest::Error },
 
+    #[snafu(display("failed to open alert queue: \n{source}"))]
+    OpenAlertQueue { source: queue::Error },
+
+    #[snafu(display("startup self-test failed: \n{source}"))]
+    SelfTest { source: twilio::Error },
+
     #[snafu(display("failed to initialize tracing: \n{source}"))]
     InitializeTelemetry {
         source: stackable_telemetry::tracing::Error,
@@ -70,6 +118,20 @@ enum RequestError {
     OpsGenie { source: opsgenie::Error },
     #[snafu(display("error when communicating with Twilio: : \n{source}"))]
     Twilio { source: twilio::Error },
+    #[snafu(display("failed to queue alert: \n{source}"))]
+    EnqueueAlert { source: queue::Error },
+    #[snafu(display("invalid alert channel [{value}], expected one of voice, sms, both"))]
+    InvalidAlertChannel { value: String },
+    #[snafu(display("invalid alert priority [{value}], expected one of P1 to P5"))]
+    InvalidAlertPriority { value: String },
+    #[snafu(display("unknown alert profile [{value}]"))]
+    InvalidAlertProfile { value: String },
+    #[snafu(display("no queued alert with id [{id}]"))]
+    AlertNotFound { id: String },
+    #[snafu(display(
+        "too many alerts from this caller, at most {limit} are allowed within {} seconds", window.as_secs()
+    ))]
+    SourceRateLimited { limit: usize, window: Duration },
 }
 
 impl http_error::Error for RequestError {
@@ -83,6 +145,12 @@ impl http_error::Error for RequestError {
         match self {
             Self::OpsGenie { source } => source.status_code(),
             Self::Twilio { source } => source.status_code(),
+            Self::InvalidAlertChannel { .. } => StatusCode::BAD_REQUEST,
+            Self::InvalidAlertPriority { .. } => StatusCode::BAD_REQUEST,
+            Self::InvalidAlertProfile { .. } => StatusCode::BAD_REQUEST,
+            Self::AlertNotFound { .. } => StatusCode::NOT_FOUND,
+            Self::SourceRateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Self::EnqueueAlert { .. } => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
@@ -121,6 +189,9 @@ async fn run() -> Result<(), StartupError> {
     let config = Config::new().context(ParseConfigSnafu)?;
 
     tracing::info!(?config, "Config parsed successfully");
+    tracing::info!(features = ?FeatureSummary::new(&config), "Features enabled at runtime");
+
+    let alert_history = AlertHistory::load(config.alert_history_config.clone()).await;
 
     tracing::debug!("Registering shutdown hook..");
     let shutdown_requested = tokio::signal::ctrl_c().map(|_| ());
@@ -140,19 +211,72 @@ async fn run() -> Result<(), StartupError> {
         .context(ConstructHttpClientSnafu)?;
     tracing::debug!(?http, "Reqwest client initialized");
 
+    match config.startup_self_test {
+        SelfTestMode::Off => tracing::info!("Startup self-test is disabled"),
+        mode => {
+            if let Err(e) = check_outgoing_number(&http, &config).await {
+                if mode == SelfTestMode::Fail {
+                    return Err(e).context(SelfTestSnafu);
+                }
+                tracing::warn!(
+                    error = &e as &dyn std::error::Error,
+                    "Startup self-test failed, starting anyway"
+                );
+            }
+        }
+    }
+
+    let heartbeat = Heartbeat::new(config.heartbeat_config.clone());
+    tokio::spawn(heartbeat.clone().watch(http.clone()));
+
+    let alert_queue = match &config.alert_mode {
+        AlertMode::Inline => None,
+        AlertMode::Queued { directory } => Some(
+            AlertQueue::open(directory.clone())
+                .await
+                .context(OpenAlertQueueSnafu)?,
+        ),
+    };
+
     use axum::Router;
     use stackable_webhook::{Options, WebhookServer};
 
+    let state = AppState {
+        http,
+        config: config.clone(),
+        schedule_metadata: ScheduleMetadataCache::default(),
+        alert_history,
+        heartbeat,
+        paging_budget: PagingBudget::new(&config.twilio_config),
+        idempotent_alerts: IdempotentAlerts::new(config.idempotency_key_ttl),
+        source_rate_limiter: config
+            .source_rate_limit
+            .map(|limit| RateLimiter::new(limit, config.source_rate_limit_window)),
+        alert_queue: alert_queue.clone(),
+        upstream_permits: Arc::new(Semaphore::new(config.max_concurrent_upstream_requests)),
+        started_at: Instant::now(),
+        // TODO: get rid of the .clone() but ... lifetimes ... shared state is not easy
+        //  https://stackoverflow.com/questions/75121484/shared-state-doesnt-work-because-of-lifetimes
+    };
+
+    // Also picks up alerts that were still queued when the service stopped
+    if let Some(alert_queue) = alert_queue {
+        tokio::spawn(process_alert_queue(state.clone(), alert_queue));
+    }
+
     let app = Router::new()
         .route("/whosoncall", get(get_person_on_call))
         .route("/alert", get(alert_on_call))
+        .route("/alert/simulate", get(simulate_alert))
+        .route("/alert/:id/status", get(alert_status))
+        .route("/alerts/recent", get(recent_alerts))
+        .route("/schedules", get(get_schedules))
         .route("/status", get(health))
-        .with_state(AppState {
-            http,
-            config: config.clone(),
-            // TODO: get rid of the .clone() but ... lifetimes ... shared state is not easy
-            //  https://stackoverflow.com/questions/75121484/shared-state-doesnt-work-because-of-lifetimes
-        });
+        .route("/ready", get(ready))
+        .route("/heartbeat", get(heartbeat_status))
+        .route("/features", get(features))
+        .route("/metrics", get(metrics))
+        .with_state(state);
 
     let server = WebhookServer::new(
         app,
@@ -177,11 +301,39 @@ async fn run() -> Result<(), StartupError> {
     Ok(server.run().await.context(RunServerSnafu)?)
 }
 
-#[derive(Debug, Deserialize, PartialEq, Eq, Hash, Clone)]
+/// The schedule a request refers to, taken from the query parameters.
+/// The parameter name alone decides how the schedule is identified: `?id=` is always an id,
+/// `?name=` always a name and `?group=` always a group, no matter what the value looks like, so a
+/// schedule named `2024` or named like a UUID is still looked up by name.
+/// If more than one of them is given, `id` takes precedence over `name`, which takes precedence
+/// over `group`.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase", untagged)]
 enum Schedule {
     ScheduleById(ScheduleRequestById),
     ScheduleByName(ScheduleRequestByName),
+    ScheduleByGroup(ScheduleRequestByGroup),
+}
+
+impl Schedule {
+    /// The id, name or group, whichever the schedule was requested by
+    fn identifier(&self) -> &str {
+        match self {
+            Schedule::ScheduleById(id) => &id.id,
+            Schedule::ScheduleByName(name) => &name.name,
+            Schedule::ScheduleByGroup(group) => &group.group,
+        }
+    }
+}
+
+impl Display for Schedule {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Schedule::ScheduleById(id) => write!(f, "id [{}]", id.id),
+            Schedule::ScheduleByName(name) => write!(f, "name [{}]", name.name),
+            Schedule::ScheduleByGroup(group) => write!(f, "group [{}]", group.group),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
@@ -196,20 +348,292 @@ struct ScheduleRequestById {
     id: String,
 }
 
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleRequestByGroup {
+    group: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase")]
 struct AlertInfo {
     username: String,
     phone_number: String,
     full_information: Vec<UserPhoneNumber>,
+    // Set when `full_information` was cut off at the configured maximum
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    full_information_truncated: bool,
+    // The id of the requested schedule, also when it was requested by name, so callers can use it
+    // for subsequent requests. Schedule groups don't have a single id.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule_id: Option<String>,
+    // Only filled when detailed information was requested
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule: Option<ScheduleMetadata>,
+}
+
+impl AlertInfo {
+    /// Bounds the size of responses, only for read endpoints, alerts always go to everybody
+    fn truncate_full_information(&mut self, max_entries: usize) {
+        if self.full_information.len() > max_entries {
+            self.full_information.truncate(max_entries);
+            self.full_information_truncated = true;
+        }
+    }
+}
+
+// Header callers can set on `/alert` to override the configured channel
+static ALERT_CHANNEL_HEADER: &str = "X-Alert-Channel";
+
+// Headers proxies use to pass on the address of the client
+static X_FORWARDED_FOR: &str = "X-Forwarded-For";
+static X_REAL_IP: &str = "X-Real-IP";
+
+// Header callers can set on `/alert` to make retries safe
+static IDEMPOTENCY_KEY_HEADER: &str = "Idempotency-Key";
+
+// Media type that can be sent in the `Accept` header to request an enveloped response
+static ENVELOPE_MEDIA_TYPE: &str = "application/vnd.wygc.envelope+json";
+
+// Media type that can be sent in the `Accept` header to request the list view
+static LIST_VIEW_MEDIA_TYPE: &str = "application/vnd.wygc.list+json";
+
+// Media type that can be sent in the `Accept` header to request the contacts view
+static CONTACTS_VIEW_MEDIA_TYPE: &str = "application/vnd.wygc.contacts+json";
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+enum WhosOnCallResponse {
+    Bare(WhosOnCallData),
+    Enveloped(Envelope<WhosOnCallData>),
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+enum WhosOnCallData {
+    Full(AlertInfo),
+    List(OnCallList),
+    Contacts(ContactList),
+}
+
+/// View of `AlertInfo` without the `username` and `phoneNumber` of the first person, for callers
+/// that only work with the full list and don't want one person to be picked for them
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct OnCallList {
+    full_information: Vec<UserPhoneNumber>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    full_information_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule: Option<ScheduleMetadata>,
+}
+
+impl From<AlertInfo> for OnCallList {
+    fn from(alert_info: AlertInfo) -> Self {
+        OnCallList {
+            full_information: alert_info.full_information,
+            full_information_truncated: alert_info.full_information_truncated,
+            schedule_id: alert_info.schedule_id,
+            schedule: alert_info.schedule,
+        }
+    }
+}
+
+/// View of `AlertInfo` with exactly one number per person on call, the same number that would be
+/// picked for `phoneNumber`, for callers that just need a contact list
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ContactList {
+    contacts: Vec<Contact>,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    contacts_truncated: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    schedule: Option<ScheduleMetadata>,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Contact {
+    name: String,
+    // Not set if the person has no number, `status` explains why
+    phone_number: Option<String>,
+    status: ContactStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tier: Option<ScheduleTier>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    team: Option<String>,
+}
+
+impl From<AlertInfo> for ContactList {
+    fn from(alert_info: AlertInfo) -> Self {
+        ContactList {
+            contacts: alert_info
+                .full_information
+                .into_iter()
+                .map(|user| Contact {
+                    phone_number: user.primary_number().cloned(),
+                    name: user.name,
+                    status: user.status,
+                    tier: user.tier,
+                    team: user.team,
+                })
+                .collect(),
+            contacts_truncated: alert_info.full_information_truncated,
+            schedule_id: alert_info.schedule_id,
+            schedule: alert_info.schedule,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct Envelope<T> {
+    data: T,
+    meta: ResponseMeta,
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct ResponseMeta {
+    as_of: DateTime<Utc>,
+    cached: bool,
+    schedule_id: Option<String>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleListOptions {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct AlertOptions {
+    // Parsed by `requested_channel`, so that invalid values are rejected like invalid headers
+    channel: Option<String>,
+    // OpsGenie priority (P1 to P5) of the alert that triggered this request
+    priority: Option<String>,
+    // Name of a configured alert profile, overrides the profile configured for the schedule
+    profile: Option<String>,
+}
+
+/// The profile named in the request, otherwise the one configured for the schedule, if any
+fn requested_profile(
+    options: &AlertOptions,
+    schedule: &Schedule,
+    alert_profiles: &HashMap<String, AlertProfile>,
+    schedule_profiles: &HashMap<String, String>,
+) -> Result<Option<AlertProfile>, RequestError> {
+    match &options.profile {
+        Some(profile) => alert_profiles
+            .get(profile.trim())
+            .copied()
+            .context(request_error::InvalidAlertProfileSnafu { value: profile })
+            .map(Some),
+        // Profiles of schedules are checked against the configured profiles on startup
+        None => Ok(schedule_profiles
+            .get(schedule.identifier())
+            .and_then(|profile| alert_profiles.get(profile))
+            .copied()),
+    }
+}
+
+/// The channel for an alert is taken from the query parameter, then the `X-Alert-Channel` header,
+/// then the profile of the alert, then the channel configured for the priority of the alert and
+/// finally the configured default.
+/// All channels are alerted in parallel, so the priority only decides how people are reached.
+fn requested_channel(
+    options: &AlertOptions,
+    headers: &HeaderMap,
+    profile: Option<AlertProfile>,
+    priority_channels: &HashMap<String, AlertChannel>,
+    default_channel: AlertChannel,
+) -> Result<AlertChannel, RequestError> {
+    // Checked even if the channel is given explicitly, an invalid priority is a mistake either way
+    let priority_channel = options
+        .priority
+        .as_ref()
+        .map(|priority| {
+            priority_channels
+                .get(&priority.trim().to_uppercase())
+                .copied()
+                .context(request_error::InvalidAlertPrioritySnafu { value: priority })
+        })
+        .transpose()?;
+    Ok(explicit_channel(options, headers)?
+        .or(profile.map(|profile| profile.channel))
+        .or(priority_channel)
+        .unwrap_or(default_channel))
 }
 
+/// The channel the caller asked for in the query parameter or the header, both are parsed the
+/// same way and ignore case
+fn explicit_channel(
+    options: &AlertOptions,
+    headers: &HeaderMap,
+) -> Result<Option<AlertChannel>, RequestError> {
+    let value = match (&options.channel, headers.get(ALERT_CHANNEL_HEADER)) {
+        (Some(value), _) => value.clone(),
+        (None, Some(value)) => String::from_utf8_lossy(value.as_bytes()).into_owned(),
+        (None, None) => return Ok(None),
+    };
+    AlertChannel::from_str(value.trim())
+        .ok()
+        .context(request_error::InvalidAlertChannelSnafu { value })
+        .map(Some)
+}
+
+#[derive(Debug, Deserialize, PartialEq, Eq, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+struct WhosOnCallOptions {
+    #[serde(default)]
+    detailed: bool,
+}
+
+/// Liveness check, the shape of the response is configurable so existing monitoring can consume
+/// it without adapters. Callers that send `Accept: text/plain` always get the plain text response.
 #[instrument(name = "health_check")]
-async fn health() -> Result<Json<Status>, http_error::JsonResponse<RequestError>> {
+async fn health(State(state): State<AppState>, headers: HeaderMap) -> Response {
     tracing::info!("Responding healthy to healthcheck");
-    Ok(Json(Status {
-        health: Health::Healthy,
-    }))
+    let text_requested = headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| accept.contains("text/plain"));
+    let format = if text_requested {
+        StatusFormat::Text
+    } else {
+        state.config.status_format
+    };
+
+    match format {
+        StatusFormat::Json => Json(Status {
+            health: Health::Healthy,
+        })
+        .into_response(),
+        StatusFormat::Actuator => Json(ActuatorStatus {
+            status: ActuatorHealth::Up,
+        })
+        .into_response(),
+        StatusFormat::Text => (StatusCode::OK, "OK").into_response(),
+    }
+}
+
+// Spring Boot actuator style health response, e.g. `{"status":"UP"}`
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+pub struct ActuatorStatus {
+    status: ActuatorHealth,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum ActuatorHealth {
+    Up,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
@@ -223,52 +647,1104 @@ pub struct Status {
 pub enum Health {
     Healthy,
     Sick,
+    Starting,
+}
+
+/// Readiness check, verifies that OpsGenie can be reached.
+/// Right after startup connectivity problems are expected to be transient (DNS, network policies
+/// settling), so during the configured grace period failures are reported as `starting` and only
+/// logged at debug level.
+#[instrument(name = "readiness_check")]
+async fn ready(State(state): State<AppState>) -> (StatusCode, Json<Status>) {
+    let AppState {
+        http,
+        config,
+        started_at,
+        ..
+    } = state;
+
+    match check_connectivity(&http, &config).await {
+        Ok(()) => {
+            tracing::debug!("Responding ready to readiness check");
+            (
+                StatusCode::OK,
+                Json(Status {
+                    health: Health::Healthy,
+                }),
+            )
+        }
+        Err(e) if started_at.elapsed() < config.readiness_grace_period => {
+            tracing::debug!(
+                error = &e as &dyn std::error::Error,
+                "Readiness check failed during startup grace period"
+            );
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(Status {
+                    health: Health::Starting,
+                }),
+            )
+        }
+        Err(e) => {
+            tracing::warn!(
+                error = &e as &dyn std::error::Error,
+                "Readiness check failed"
+            );
+            (
+                StatusCode::SERVICE_UNAVAILABLE,
+                Json(Status {
+                    health: Health::Sick,
+                }),
+            )
+        }
+    }
 }
 
 #[instrument(name = "who_is_on_call")]
 async fn get_person_on_call(
     State(state): State<AppState>,
     Query(requested_schedule): Query<Schedule>,
+    Query(options): Query<WhosOnCallOptions>,
     headers: HeaderMap,
-) -> Result<Json<AlertInfo>, http_error::JsonResponse<RequestError>> {
-    let AppState { http, config } = state;
-    tracing::info!(
+) -> Result<Json<WhosOnCallResponse>, http_error::JsonResponse<RequestError>> {
+    let AppState {
+        http,
+        config,
+        schedule_metadata,
+        heartbeat,
+        upstream_permits,
+        ..
+    } = state;
+    // Dashboards poll this endpoint a lot, so how loudly this is logged is configurable
+    event_at_level!(
+        config.whosoncall_log_level,
         ?requested_schedule,
+        ?options,
         "Got request to look up on call persons for schedule"
     );
-    Ok(Json(
-        get_oncall_number(&requested_schedule, &http, &config)
-            .await
-            .context(request_error::OpsGenieSnafu)?,
-    ))
+    let mut alert_info = get_oncall_number(&requested_schedule, &http, &config, &upstream_permits)
+        .await
+        .inspect_err(|e| {
+            // Don't keep serving metadata for schedules that have been deleted
+            if e.is_schedule_not_found() {
+                schedule_metadata.invalidate(&requested_schedule);
+            }
+        })
+        .context(request_error::OpsGenieSnafu)?;
+
+    if options.detailed {
+        alert_info.schedule = get_schedule_metadata(
+            &requested_schedule,
+            &http,
+            &config.opsgenie_config,
+            &schedule_metadata,
+        )
+        .await
+        .context(request_error::OpsGenieSnafu)?;
+    }
+
+    heartbeat.record_success();
+
+    if let Some(max_entries) = config.whosoncall_max_entries {
+        alert_info.truncate_full_information(max_entries);
+    }
+
+    // Callers can ask for the envelope and the list view explicitly, otherwise the configured
+    // defaults apply
+    let accept = headers
+        .get(ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .unwrap_or_default();
+    let envelope_requested = accept.contains(ENVELOPE_MEDIA_TYPE);
+    let view = if accept.contains(CONTACTS_VIEW_MEDIA_TYPE) {
+        WhosOnCallView::Contacts
+    } else if accept.contains(LIST_VIEW_MEDIA_TYPE) {
+        WhosOnCallView::List
+    } else {
+        config.whosoncall_view
+    };
+
+    let schedule_id = alert_info.schedule_id.clone();
+    let data = match view {
+        WhosOnCallView::Full => WhosOnCallData::Full(alert_info),
+        WhosOnCallView::List => WhosOnCallData::List(alert_info.into()),
+        WhosOnCallView::Contacts => WhosOnCallData::Contacts(alert_info.into()),
+    };
+
+    if envelope_requested || config.response_envelope == ResponseEnvelope::Enveloped {
+        Ok(Json(WhosOnCallResponse::Enveloped(Envelope {
+            meta: ResponseMeta {
+                as_of: Utc::now(),
+                // On call information is always fetched live from OpsGenie
+                cached: false,
+                schedule_id,
+            },
+            data,
+        })))
+    } else {
+        Ok(Json(WhosOnCallResponse::Bare(data)))
+    }
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(untagged)]
+enum AlertResponse {
+    Completed(AlertResult),
+    Accepted(AcceptedAlert),
+}
+
+/// Returned instead of the result when alerts are queued
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AcceptedAlert {
+    id: String,
+    accepted_at: DateTime<Utc>,
+    // Can be polled for the outcome, also sent as `Location` header
+    status_url: String,
 }
 
 #[instrument(name = "alert")]
 async fn alert_on_call(
     State(state): State<AppState>,
     Query(requested_alert): Query<Schedule>,
-) -> Result<Json<AlertResult>, http_error::JsonResponse<RequestError>> {
-    let AppState { http, config } = state;
-    tracing::info!(?requested_alert, "Got alert request!");
+    Query(options): Query<AlertOptions>,
+    peer: Option<ConnectInfo<SocketAddr>>,
+    headers: HeaderMap,
+) -> Result<Response, http_error::JsonResponse<RequestError>> {
+    event_at_level!(
+        state.config.alert_log_level,
+        ?requested_alert,
+        ?options,
+        "Got alert request!"
+    );
+    // Invalid requests are rejected before they use up any of the caller's limit
+    let profile = requested_profile(
+        &options,
+        &requested_alert,
+        &state.config.alert_profiles,
+        &state.config.schedule_profiles,
+    )?;
+    let channel = requested_channel(
+        &options,
+        &headers,
+        profile,
+        &state.config.twilio_config.priority_channels,
+        state.config.twilio_config.default_channel,
+    )?;
+
+    // Critical alerts can be exempt, they have to get through even if the caller is noisy
+    let rate_limit_exempt = profile.is_some_and(|profile| profile.rate_limit_exempt);
+    if rate_limit_exempt {
+        tracing::info!("Alert profile is exempt from the rate limit, not rate limiting it");
+    }
+    // Checked before any other work, a caller that is over its limit shouldn't cause any
+    if let Some(limiter) = state
+        .source_rate_limiter
+        .as_ref()
+        .filter(|_| !rate_limit_exempt)
+    {
+        let peer = peer.map(|ConnectInfo(address)| address);
+        match source_identity(&headers, peer, state.config.trust_forwarded_headers) {
+            Some(caller) => {
+                if !limiter.try_acquire(caller.clone()) {
+                    tracing::warn!(caller, "Caller exceeded its alert rate limit");
+                    return Err(request_error::SourceRateLimitedSnafu {
+                        limit: state.config.source_rate_limit.unwrap_or_default(),
+                        window: state.config.source_rate_limit_window,
+                    }
+                    .build()
+                    .into());
+                }
+            }
+            // Sharing one limit would let a single caller block everybody we can't tell apart
+            None => tracing::error!(
+                "The request has no client address to tell callers apart, not rate limiting it"
+            ),
+        }
+    }
+
+    // A retried alert with the same key gets the result of the first one instead of paging again
+    let idempotency_key = headers
+        .get(IDEMPOTENCY_KEY_HEADER)
+        .and_then(|key| key.to_str().ok());
+
+    if let Some(alert_queue) = &state.alert_queue {
+        let queued = alert_queue
+            .enqueue(requested_alert, channel, idempotency_key.map(String::from))
+            .await
+            .context(request_error::EnqueueAlertSnafu)?;
+        event_at_level!(state.config.alert_log_level, queued.id, "Alert queued");
+        let status_url = alert_status_url(&queued.id, state.config.external_base_url.as_ref());
+        return Ok((
+            StatusCode::ACCEPTED,
+            [(LOCATION, status_url.clone())],
+            Json(AlertResponse::Accepted(AcceptedAlert {
+                id: queued.id,
+                accepted_at: queued.accepted_at,
+                status_url,
+            })),
+        )
+            .into_response());
+    }
+
+    let result = execute_alert(&state, requested_alert, channel, idempotency_key, None).await?;
+    Ok((StatusCode::OK, Json(AlertResponse::Completed(result))).into_response())
+}
 
-    let schedule = requested_alert.clone();
-    let people_to_alert = get_oncall_number(&schedule, &http, &config)
+/// Identifies the caller for rate limiting by the address of the peer that connected to us.
+/// Behind a proxy that is always the proxy, so if configured the client address the proxy reports
+/// is used instead. Anything else the caller sends (e.g. a token) can be changed freely and isn't
+/// checked by this service, so it can't be used to tell callers apart.
+/// Returns `None` if the caller can't be identified.
+/// The webhook server doesn't provide the peer address, which is why the rate limit can only be
+/// configured together with the proxy headers.
+fn source_identity(
+    headers: &HeaderMap,
+    peer: Option<SocketAddr>,
+    trust_forwarded_headers: bool,
+) -> Option<String> {
+    let mut forwarded = None;
+    if trust_forwarded_headers {
+        // The last entry is the one added by the proxy, everything before it comes from the
+        // client and can be made up
+        let forwarded_for = headers
+            .get(X_FORWARDED_FOR)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.rsplit(',').next());
+        let real_ip = headers.get(X_REAL_IP).and_then(|value| value.to_str().ok());
+        forwarded = forwarded_for
+            .or(real_ip)
+            .map(str::trim)
+            .filter(|address| !address.is_empty())
+            .map(str::to_string);
+    }
+    forwarded
+        .or_else(|| peer.map(|peer| peer.ip().to_string()))
+        .map(|address| format!("address:{address}"))
+}
+
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct AlertStatusResponse {
+    id: String,
+    #[serde(flatten)]
+    status: AlertStatus,
+}
+
+/// Where the outcome of a queued alert can be polled, relative to the host the request was sent to
+/// unless the external base url is configured
+fn alert_status_url(id: &str, external_base_url: Option<&Url>) -> String {
+    let path = format!("alert/{id}/status");
+    // Ids only contain characters that are valid in a path, so joining them doesn't fail
+    match external_base_url.and_then(|base_url| base_url.join(&path).ok()) {
+        Some(url) => url.to_string(),
+        None => format!("/{path}"),
+    }
+}
+
+/// Outcome of an alert that was accepted with `202`, only available when alerts are queued
+#[instrument(name = "alert_status")]
+async fn alert_status(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<AlertStatusResponse>, http_error::JsonResponse<RequestError>> {
+    let status = match &state.alert_queue {
+        Some(alert_queue) => alert_queue.status(&id).await,
+        None => None,
+    }
+    .context(request_error::AlertNotFoundSnafu { id: &id })?;
+    Ok(Json(AlertStatusResponse { id, status }))
+}
+
+/// Looks up who is on call for the schedule and calls them, this is the same for alerts that are
+/// handled inline and those that are processed from the queue.
+/// An alert with an idempotency key that already reached somebody for this schedule is not sent
+/// again, its result is returned instead. Queued alerts pass their `progress`, numbers that were
+/// dialed before the alert was interrupted are not dialed again.
+async fn execute_alert(
+    state: &AppState,
+    schedule: Schedule,
+    channel: AlertChannel,
+    idempotency_key: Option<&str>,
+    progress: Option<&DialProgress>,
+) -> Result<AlertResult, RequestError> {
+    state
+        .idempotent_alerts
+        .run(&schedule, idempotency_key, || {
+            dispatch_alert(state, schedule.clone(), channel, idempotency_key, progress)
+        })
+        .await
+}
+
+async fn dispatch_alert(
+    state: &AppState,
+    schedule: Schedule,
+    channel: AlertChannel,
+    idempotency_key: Option<&str>,
+    progress: Option<&DialProgress>,
+) -> Result<AlertResult, RequestError> {
+    let AppState {
+        http,
+        config,
+        alert_history,
+        heartbeat,
+        paging_budget,
+        upstream_permits,
+        ..
+    } = state;
+
+    let now = Utc::now();
+    if let Some(reason) = maintenance_suppression(config, &schedule, now) {
+        tracing::warn!(%schedule, reason, "Not alerting anybody");
+        if config.notify_suppressed_alerts {
+            notify_suppressed_alert(&schedule, &reason, http, config).await;
+        }
+        let result = AlertResult {
+            overall_result: OverallResult::Suppressed,
+            detailed_result: vec![],
+            skipped_numbers: vec![],
+            idempotency_key: idempotency_key.map(String::from),
+            suppressed: Some(reason),
+            fallbacks: vec![],
+        };
+        heartbeat.record_success();
+        alert_history
+            .record(AlertRecord {
+                timestamp: now,
+                schedule,
+                result: result.clone(),
+            })
+            .await;
+        return Ok(result);
+    }
+
+    let people_to_alert = get_oncall_number(&schedule, http, config, upstream_permits)
         .await
         .context(request_error::OpsGenieSnafu)?;
 
     // Collect all phone number that we need to ring into one vec
-    let numbers: Vec<String> = people_to_alert
-        .full_information
+    let mut plan = plan_alert(&schedule, &people_to_alert, channel, config)
+        .context(request_error::TwilioSnafu)?;
+
+    // We can't know how calls that were placed before an interruption went, so they are reported
+    // as unknown instead of being placed again
+    let mut already_dialed = Vec::new();
+    let mut fallback_numbers = Vec::new();
+    if let Some(progress) = progress {
+        let dialed_numbers = progress.dialed_numbers().await;
+        fallback_numbers = progress.fallback_numbers().await;
+        let (dialed, remaining) = std::mem::take(&mut plan.calls)
+            .into_iter()
+            .partition(|call| dialed_numbers.contains(&call.number));
+        already_dialed = dialed;
+        plan.calls = remaining;
+    }
+
+    event_at_level!(
+        config.alert_log_level,
+        "Will call these phones: [{:?}]",
+        plan.calls
+    );
+
+    if let Err(e) = paging_budget.try_spend(plan.paging_cost()) {
+        tracing::error!(
+            error = &e as &dyn std::error::Error,
+            %schedule,
+            "Refusing to alert"
+        );
+        // Every refused alert would create another one while the budget is used up
+        if paging_budget.report_exceeded() {
+            if let Err(alert_error) =
+                create_paging_budget_alert(&schedule, &e.to_string(), http, config).await
+            {
+                tracing::error!(
+                    error = &alert_error as &dyn std::error::Error,
+                    "Creating an OpsGenie alert for the exceeded paging budget failed"
+                );
+            }
+        }
+        return Err(e).context(request_error::TwilioSnafu);
+    }
+
+    let result = alert(
+        &plan,
+        progress,
+        paging_budget,
+        http,
+        config,
+        upstream_permits,
+    )
+    .await
+    .map(|mut result| {
+        result.idempotency_key = idempotency_key.map(String::from);
+        report_already_dialed(&mut result, already_dialed, &fallback_numbers);
+        result
+    });
+
+    // If nobody could be reached at all make sure this doesn't go unnoticed
+    if config.opsgenie_config.alert_on_total_failure {
+        let failure_details = match &result {
+            Ok(result) if result.overall_result == OverallResult::Failure => {
+                Some(format!("{:#?}", result.detailed_result))
+            }
+            Ok(_) => None,
+            Err(e) => Some(e.to_string()),
+        };
+        if let Some(failure_details) = failure_details {
+            if let Err(e) =
+                create_total_failure_alert(&schedule, &failure_details, http, config).await
+            {
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    "Nobody could be reached and creating an OpsGenie alert for this failed as well"
+                );
+            }
+        }
+    }
+
+    let result = result.context(request_error::TwilioSnafu)?;
+    if result.overall_result != OverallResult::Failure {
+        heartbeat.record_success();
+    }
+
+    alert_history
+        .record(AlertRecord {
+            timestamp: Utc::now(),
+            schedule,
+            result: result.clone(),
+        })
+        .await;
+
+    Ok(result)
+}
+
+/// Adds the calls that were placed before a queued alert was interrupted to its result
+fn report_already_dialed(
+    result: &mut AlertResult,
+    already_dialed: Vec<PlannedCall>,
+    fallback_numbers: &[String],
+) {
+    if already_dialed.is_empty() {
+        return;
+    }
+    for PlannedCall { number, usernames } in already_dialed {
+        if fallback_numbers.contains(&number) {
+            result.fallbacks.push(DialNumberResult::Unknown {
+                number: number.clone(),
+                usernames: usernames.clone(),
+                status: "called as a fallback before the alert was interrupted".to_string(),
+            });
+        }
+        result.detailed_result.push(DialNumberResult::Unknown {
+            number,
+            usernames,
+            status: "dialed before the alert was interrupted".to_string(),
+        });
+    }
+    result.update_overall_result();
+}
+
+/// Why an alert for the schedule is suppressed at `now`, `None` if no maintenance window is active
+fn maintenance_suppression(
+    config: &Config,
+    schedule: &Schedule,
+    now: DateTime<Utc>,
+) -> Option<String> {
+    config
+        .maintenance_windows
         .iter()
-        .map(|person| person.phone.clone())
-        .flatten()
-        .collect();
+        .find(|window| window.is_active(now, schedule.identifier()))
+        .map(|window| {
+            format!(
+                "suppressed: maintenance window from {} to {}",
+                window.start, window.end
+            )
+        })
+}
+
+/// Lets the Slack channel know that a page was suppressed, failing to do so doesn't change the
+/// outcome of the alert
+async fn notify_suppressed_alert(
+    schedule: &Schedule,
+    reason: &str,
+    http: &reqwest::Client,
+    config: &Config,
+) {
+    let Some(slack_config) = &config.slack_config else {
+        return;
+    };
+    let result = http
+        .post(slack_config.url.clone())
+        .header(AUTHORIZATION, slack_config.token.expose_secret().clone().0)
+        .json(&serde_json::json!({
+            "text": format!("Alert for schedule {schedule} was {reason}, nobody was paged")
+        }))
+        .send()
+        .await
+        .and_then(|response| response.error_for_status());
+    if let Err(error) = result {
+        tracing::error!(
+            error = &error as &dyn std::error::Error,
+            "Failed to notify Slack about suppressed alert"
+        );
+    }
+}
+
+/// Works through the queued alerts, oldest first.
+/// An alert is only removed from the queue once it has been processed, so alerts that were
+/// interrupted by a crash are processed again after a restart. Every number is recorded in the
+/// queued alert before it is dialed, numbers that were dialed before the crash are not dialed
+/// again.
+async fn process_alert_queue(state: AppState, alert_queue: AlertQueue) {
+    loop {
+        let pending = alert_queue.pending().await.unwrap_or_else(|e| {
+            tracing::error!(
+                error = &e as &dyn std::error::Error,
+                "Failed to list queued alerts"
+            );
+            Vec::new()
+        });
+        for path in pending {
+            match alert_queue.read(&path).await {
+                Ok(queued) => {
+                    tracing::info!(queued.id, ?queued.schedule, "Processing queued alert");
+                    let progress = DialProgress::new(alert_queue.clone(), queued.clone());
+                    let status = match execute_alert(
+                        &state,
+                        queued.schedule,
+                        queued.channel,
+                        queued.idempotency_key.as_deref(),
+                        Some(&progress),
+                    )
+                    .await
+                    {
+                        Ok(result) => {
+                            tracing::info!(queued.id, ?result, "Queued alert processed");
+                            AlertStatus::Completed { result }
+                        }
+                        Err(e) => {
+                            tracing::error!(
+                                error = &e as &dyn std::error::Error,
+                                queued.id,
+                                "Queued alert failed"
+                            );
+                            AlertStatus::Failed {
+                                error: e.to_string(),
+                            }
+                        }
+                    };
+                    finish_queued_alert(&alert_queue, &queued.id, status).await;
+                }
+                // There is nothing left to retry for an alert that can't be read
+                Err(e) => {
+                    tracing::error!(
+                        error = &e as &dyn std::error::Error,
+                        "Dropping unreadable queued alert"
+                    );
+                    if let Some(id) = path.file_stem().and_then(|id| id.to_str()) {
+                        let status = AlertStatus::Failed {
+                            error: e.to_string(),
+                        };
+                        finish_queued_alert(&alert_queue, id, status).await;
+                    }
+                }
+            }
+            if let Err(e) = alert_queue.complete(&path).await {
+                // Stop here instead of processing the same alert over and over
+                tracing::error!(
+                    error = &e as &dyn std::error::Error,
+                    "Failed to remove processed alert from the queue, stopping the queue worker"
+                );
+                return;
+            }
+        }
+        alert_queue.wait(ALERT_QUEUE_POLL_INTERVAL).await;
+    }
+}
+
+async fn finish_queued_alert(alert_queue: &AlertQueue, id: &str, status: AlertStatus) {
+    if let Err(e) = alert_queue.finish(id, status).await {
+        // The alert was processed all the same, only its status can't be looked up
+        tracing::error!(
+            error = &e as &dyn std::error::Error,
+            id,
+            "Failed to store the outcome of the queued alert"
+        );
+    }
+}
+
+/// Reports when `/alert` or `/whosoncall` last succeeded, responds with `503` if that was longer
+/// ago than the configured heartbeat window
+#[instrument(name = "heartbeat")]
+async fn heartbeat_status(State(state): State<AppState>) -> (StatusCode, Json<HeartbeatStatus>) {
+    let status = state.heartbeat.status();
+    if status.stale {
+        (StatusCode::SERVICE_UNAVAILABLE, Json(status))
+    } else {
+        (StatusCode::OK, Json(status))
+    }
+}
+
+/// What this deployment is set up to do, so operators don't have to read the configuration.
+/// Only says whether something is enabled, never where it points to or how it authenticates.
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct FeatureSummary {
+    version: &'static str,
+    // The crate defines no Cargo features, everything is switched on at runtime. Listed anyway so
+    // callers don't mistake a missing field for an unknown build.
+    compiled_features: Vec<&'static str>,
+    queued_alerts: bool,
+    default_channel: AlertChannel,
+    sms_fallback_to_voice: bool,
+    slack_configured: bool,
+    alert_history_persisted: bool,
+    heartbeat_monitoring: bool,
+    opsgenie_alert_on_total_failure: bool,
+    opsgenie_team_expansion: bool,
+    schedule_groups: usize,
+    paging_budget: Option<usize>,
+    source_rate_limit: Option<usize>,
+    startup_self_test: bool,
+}
+
+impl FeatureSummary {
+    fn new(config: &Config) -> Self {
+        FeatureSummary {
+            version: env!("CARGO_PKG_VERSION"),
+            compiled_features: Vec::new(),
+            queued_alerts: matches!(config.alert_mode, AlertMode::Queued { .. }),
+            default_channel: config.twilio_config.default_channel,
+            sms_fallback_to_voice: config.twilio_config.sms_fallback_to_voice,
+            slack_configured: config.slack_config.is_some(),
+            alert_history_persisted: config.alert_history_config.file.is_some(),
+            heartbeat_monitoring: config.heartbeat_config.window.is_some(),
+            opsgenie_alert_on_total_failure: config.opsgenie_config.alert_on_total_failure,
+            opsgenie_team_expansion: config.opsgenie_config.expand_teams,
+            schedule_groups: config.opsgenie_config.schedule_groups.len(),
+            paging_budget: config.twilio_config.paging_budget,
+            source_rate_limit: config.source_rate_limit,
+            startup_self_test: config.startup_self_test != SelfTestMode::Off,
+        }
+    }
+}
 
-    tracing::info!("Will call these phones: [{:?}]", numbers);
+#[instrument(name = "features")]
+async fn features(State(state): State<AppState>) -> Json<FeatureSummary> {
+    Json(FeatureSummary::new(&state.config))
+}
+
+#[instrument(name = "metrics")]
+async fn metrics() -> impl IntoResponse {
+    (
+        [(CONTENT_TYPE, "text/plain; version=0.0.4")],
+        contact_method_metrics(),
+    )
+}
+
+#[instrument(name = "recent_alerts")]
+async fn recent_alerts(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<AlertRecord>>, http_error::JsonResponse<RequestError>> {
+    Ok(Json(state.alert_history.recent().await))
+}
+
+#[instrument(name = "simulate_alert")]
+async fn simulate_alert(
+    State(state): State<AppState>,
+    Query(requested_alert): Query<Schedule>,
+    Query(options): Query<AlertOptions>,
+    headers: HeaderMap,
+) -> Result<Json<SimulatedAlert>, http_error::JsonResponse<RequestError>> {
+    let AppState {
+        http,
+        config,
+        paging_budget,
+        upstream_permits,
+        ..
+    } = state;
+    tracing::info!(?requested_alert, ?options, "Got request to simulate alert");
+    let profile = requested_profile(
+        &options,
+        &requested_alert,
+        &config.alert_profiles,
+        &config.schedule_profiles,
+    )?;
+    let channel = requested_channel(
+        &options,
+        &headers,
+        profile,
+        &config.twilio_config.priority_channels,
+        config.twilio_config.default_channel,
+    )?;
+
+    // Same order of checks as a real alert, nobody is looked up while alerts are suppressed
+    if let Some(reason) = maintenance_suppression(&config, &requested_alert, Utc::now()) {
+        tracing::info!(reason, "Simulated alert, it would be suppressed");
+        return Ok(Json(SimulatedAlert {
+            suppressed: Some(reason),
+            paging_budget_exceeded: None,
+            plan: None,
+        }));
+    }
+
+    let people_to_alert = get_oncall_number(&requested_alert, &http, &config, &upstream_permits)
+        .await
+        .context(request_error::OpsGenieSnafu)?;
+
+    let plan = plan_alert(&requested_alert, &people_to_alert, channel, &config)
+        .context(request_error::TwilioSnafu)?;
+    let paging_budget_exceeded = paging_budget
+        .check(plan.paging_cost())
+        .err()
+        .map(|e| e.to_string());
+    tracing::info!(
+        ?plan,
+        paging_budget_exceeded,
+        "Simulated alert, no calls were placed"
+    );
+
+    Ok(Json(SimulatedAlert {
+        suppressed: None,
+        paging_budget_exceeded,
+        plan: Some(plan),
+    }))
+}
+
+/// What `/alert` would do for the same request right now
+#[derive(Debug, Serialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+struct SimulatedAlert {
+    // Why nobody would be alerted, only set if a maintenance window is active. There is no plan
+    // then, because on call persons are not looked up for suppressed alerts.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    suppressed: Option<String>,
+    // Set if the plan needs more calls than are left in the paging budget, nobody would be
+    // alerted then
+    #[serde(skip_serializing_if = "Option::is_none")]
+    paging_budget_exceeded: Option<String>,
+    #[serde(flatten)]
+    plan: Option<AlertPlan>,
+}
+
+#[instrument(name = "list_schedules")]
+async fn get_schedules(
+    State(state): State<AppState>,
+    Query(options): Query<ScheduleListOptions>,
+) -> Result<Json<ScheduleList>, http_error::JsonResponse<RequestError>> {
+    let AppState { http, config, .. } = state;
+    tracing::info!(?options, "Got request to list schedules");
+
+    // Without an explicit limit callers get as many schedules as we are willing to return
+    let limit = options
+        .limit
+        .unwrap_or(config.opsgenie_config.max_schedules_per_page);
 
     Ok(Json(
-        alert(&numbers, &http, &config)
+        list_schedules(options.offset, limit, &http, &config)
             .await
-            .context(request_error::TwilioSnafu)?,
+            .context(request_error::OpsGenieSnafu)?,
     ))
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        alert_status_url, explicit_channel, report_already_dialed, requested_channel,
+        requested_profile, source_identity, AlertInfo, AlertOptions, Contact, ContactList,
+        ContactStatus, RequestError, Schedule, ScheduleRequestByGroup, ScheduleRequestById,
+        ScheduleRequestByName, UserPhoneNumber, ALERT_CHANNEL_HEADER,
+    };
+    use crate::config::{AlertChannel, AlertProfile};
+    use crate::opsgenie::PhoneCapabilities;
+    use crate::twilio::{AlertResult, DialNumberResult, OverallResult, PlannedCall};
+    use axum::extract::Query;
+    use axum::http::{HeaderMap, HeaderValue, Uri};
+    use reqwest::Url;
+    use rstest::rstest;
+    use std::collections::HashMap;
+
+    fn by_id(id: &str) -> Schedule {
+        Schedule::ScheduleById(ScheduleRequestById { id: id.to_string() })
+    }
+
+    fn alert_profiles() -> HashMap<String, AlertProfile> {
+        HashMap::from([
+            (
+                "critical".to_string(),
+                AlertProfile {
+                    channel: AlertChannel::Voice,
+                    rate_limit_exempt: true,
+                },
+            ),
+            (
+                "routine".to_string(),
+                AlertProfile {
+                    channel: AlertChannel::Sms,
+                    rate_limit_exempt: false,
+                },
+            ),
+        ])
+    }
+
+    fn by_name(name: &str) -> Schedule {
+        Schedule::ScheduleByName(ScheduleRequestByName {
+            name: name.to_string(),
+        })
+    }
+
+    #[rstest]
+    #[case::numeric_name("/alert?name=2024", Some(by_name("2024")))]
+    #[case::uuid_like_name(
+        "/alert?name=0b6e5b2c-4f0a-4d2e-9c3a-1f6f0c1d2e3f",
+        Some(by_name("0b6e5b2c-4f0a-4d2e-9c3a-1f6f0c1d2e3f"))
+    )]
+    #[case::numeric_id("/alert?id=2024", Some(by_id("2024")))]
+    #[case::name_like_id("/alert?id=ops_team_schedule", Some(by_id("ops_team_schedule")))]
+    #[case::group(
+        "/alert?group=ops",
+        Some(Schedule::ScheduleByGroup(ScheduleRequestByGroup {
+            group: "ops".to_string()
+        }))
+    )]
+    #[case::id_before_name("/alert?name=ops&id=2024", Some(by_id("2024")))]
+    #[case::other_parameters_are_ignored("/alert?name=2024&detailed=true", Some(by_name("2024")))]
+    #[case::missing("/alert?detailed=true", None)]
+    fn test_schedule_from_query(#[case] uri: &str, #[case] expected: Option<Schedule>) {
+        let uri: Uri = uri.parse().unwrap();
+        let schedule = Query::<Schedule>::try_from_uri(&uri)
+            .ok()
+            .map(|Query(schedule)| schedule);
+        assert_eq!(schedule, expected);
+    }
+
+    #[rstest]
+    #[case::query("/alert?id=ops&channel=sms", None, Some(AlertChannel::Sms))]
+    #[case::query_ignores_case("/alert?id=ops&channel=SMS", None, Some(AlertChannel::Sms))]
+    #[case::header("/alert?id=ops", Some("Both"), Some(AlertChannel::Both))]
+    #[case::query_before_header(
+        "/alert?id=ops&channel=voice",
+        Some("sms"),
+        Some(AlertChannel::Voice)
+    )]
+    #[case::none("/alert?id=ops", None, None)]
+    fn test_explicit_channel(
+        #[case] uri: &str,
+        #[case] header: Option<&str>,
+        #[case] expected: Option<AlertChannel>,
+    ) {
+        let Query(options) = Query::<AlertOptions>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(header) = header {
+            headers.insert(ALERT_CHANNEL_HEADER, HeaderValue::from_str(header).unwrap());
+        }
+        assert_eq!(explicit_channel(&options, &headers).unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::query("/alert?id=ops&channel=fax", None)]
+    #[case::header("/alert?id=ops", Some("fax"))]
+    fn test_explicit_channel_rejects_unknown_channel(
+        #[case] uri: &str,
+        #[case] header: Option<&str>,
+    ) {
+        let Query(options) = Query::<AlertOptions>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        let mut headers = HeaderMap::new();
+        if let Some(header) = header {
+            headers.insert(ALERT_CHANNEL_HEADER, HeaderValue::from_str(header).unwrap());
+        }
+        assert!(matches!(
+            explicit_channel(&options, &headers),
+            Err(RequestError::InvalidAlertChannel { value }) if value == "fax"
+        ));
+    }
+
+    #[rstest]
+    #[case::priority("/alert?id=ops&priority=p1", Ok(AlertChannel::Voice))]
+    #[case::channel_before_priority(
+        "/alert?id=ops&priority=P1&channel=both",
+        Ok(AlertChannel::Both)
+    )]
+    #[case::default("/alert?id=ops", Ok(AlertChannel::Sms))]
+    #[case::invalid_priority("/alert?id=ops&priority=P9", Err("P9"))]
+    #[case::invalid_priority_with_channel("/alert?id=ops&channel=sms&priority=P9", Err("P9"))]
+    fn test_requested_channel(#[case] uri: &str, #[case] expected: Result<AlertChannel, &str>) {
+        let Query(options) = Query::<AlertOptions>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        let priority_channels = HashMap::from([("P1".to_string(), AlertChannel::Voice)]);
+        let channel = requested_channel(
+            &options,
+            &HeaderMap::new(),
+            None,
+            &priority_channels,
+            AlertChannel::Sms,
+        );
+        match expected {
+            Ok(expected) => assert_eq!(channel.unwrap(), expected),
+            Err(priority) => assert!(matches!(
+                channel,
+                Err(RequestError::InvalidAlertPriority { value }) if value == priority
+            )),
+        }
+    }
+
+    #[rstest]
+    #[case::requested("/alert?id=dev&profile=critical", Some(AlertChannel::Voice))]
+    #[case::schedule_default("/alert?id=ops", Some(AlertChannel::Sms))]
+    #[case::request_before_schedule("/alert?id=ops&profile=critical", Some(AlertChannel::Voice))]
+    #[case::none("/alert?id=dev", None)]
+    fn test_requested_profile(#[case] uri: &str, #[case] expected: Option<AlertChannel>) {
+        let Query(schedule) = Query::<Schedule>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        let Query(options) = Query::<AlertOptions>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        let profile = requested_profile(
+            &options,
+            &schedule,
+            &alert_profiles(),
+            &HashMap::from([("ops".to_string(), "routine".to_string())]),
+        )
+        .unwrap();
+        assert_eq!(profile.map(|profile| profile.channel), expected);
+    }
+
+    #[test]
+    fn test_requested_profile_rejects_unknown_profile() {
+        let uri = "/alert?id=ops&profile=urgent".parse().unwrap();
+        let Query(options) = Query::<AlertOptions>::try_from_uri(&uri).unwrap();
+        assert!(matches!(
+            requested_profile(&options, &by_id("ops"), &alert_profiles(), &HashMap::new()),
+            Err(RequestError::InvalidAlertProfile { value }) if value == "urgent"
+        ));
+    }
+
+    #[rstest]
+    #[case::profile_before_priority("/alert?id=ops&priority=P1", AlertChannel::Sms)]
+    #[case::channel_before_profile("/alert?id=ops&channel=both", AlertChannel::Both)]
+    fn test_requested_channel_with_profile(#[case] uri: &str, #[case] expected: AlertChannel) {
+        let Query(options) = Query::<AlertOptions>::try_from_uri(&uri.parse().unwrap()).unwrap();
+        let channel = requested_channel(
+            &options,
+            &HeaderMap::new(),
+            alert_profiles().get("routine").copied(),
+            &HashMap::from([("P1".to_string(), AlertChannel::Voice)]),
+            AlertChannel::Voice,
+        );
+        assert_eq!(channel.unwrap(), expected);
+    }
+
+    #[rstest]
+    #[case::relative(None, "/alert/20261016T120000Z-1-0/status")]
+    #[case::mounted(
+        Some("https://example.com/oncall/"),
+        "https://example.com/oncall/alert/20261016T120000Z-1-0/status"
+    )]
+    fn test_alert_status_url(#[case] external_base_url: Option<&str>, #[case] expected: &str) {
+        let external_base_url = external_base_url.map(|url| Url::parse(url).unwrap());
+        assert_eq!(
+            alert_status_url("20261016T120000Z-1-0", external_base_url.as_ref()),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_report_already_dialed_includes_fallback_calls() {
+        let call = |number: &str| PlannedCall {
+            number: number.to_string(),
+            usernames: vec!["alice".to_string()],
+        };
+        let mut result = AlertResult {
+            overall_result: OverallResult::Success,
+            detailed_result: vec![],
+            skipped_numbers: vec![],
+            idempotency_key: None,
+            suppressed: None,
+            fallbacks: vec![],
+        };
+
+        report_already_dialed(
+            &mut result,
+            vec![call("+49111"), call("+49222")],
+            &["+49222".to_string()],
+        );
+        assert_eq!(result.detailed_result.len(), 2);
+        assert!(matches!(
+            result.fallbacks.as_slice(),
+            [DialNumberResult::Unknown { number, .. }] if number == "+49222"
+        ));
+        assert_eq!(result.overall_result, OverallResult::Failure);
+    }
+
+    #[test]
+    fn test_contact_list_picks_one_number_per_person() {
+        let user = |name: &str, phone: &[&str], status| UserPhoneNumber {
+            name: name.to_string(),
+            phone: phone.iter().map(|number| number.to_string()).collect(),
+            status,
+            tier: None,
+            team: None,
+            capabilities: PhoneCapabilities::new(),
+        };
+        let alert_info = AlertInfo {
+            username: "alice".to_string(),
+            phone_number: "+491".to_string(),
+            full_information: vec![
+                user("alice", &["+491", "+492"], ContactStatus::Ok),
+                user("bob", &[], ContactStatus::NoContacts),
+            ],
+            full_information_truncated: false,
+            schedule_id: Some("ops".to_string()),
+            schedule: None,
+        };
+        let contact = |name: &str, phone_number: Option<&str>, status| Contact {
+            name: name.to_string(),
+            phone_number: phone_number.map(str::to_string),
+            status,
+            tier: None,
+            team: None,
+        };
+        assert_eq!(
+            ContactList::from(alert_info),
+            ContactList {
+                contacts: vec![
+                    contact("alice", Some("+491"), ContactStatus::Ok),
+                    contact("bob", None, ContactStatus::NoContacts),
+                ],
+                contacts_truncated: false,
+                schedule_id: Some("ops".to_string()),
+                schedule: None,
+            }
+        );
+    }
+
+    #[rstest]
+    #[case::peer(&[], false, Some("address:192.168.0.1"))]
+    #[case::untrusted_headers(
+        &[("X-Forwarded-For", "10.0.0.1"), ("X-Real-IP", "10.0.0.3")],
+        false,
+        Some("address:192.168.0.1")
+    )]
+    #[case::forwarded_for(&[("X-Forwarded-For", "10.0.0.1, 10.0.0.2")], true, Some("address:10.0.0.2"))]
+    #[case::real_ip(&[("X-Real-IP", "10.0.0.3")], true, Some("address:10.0.0.3"))]
+    #[case::trusted_without_headers(&[], true, Some("address:192.168.0.1"))]
+    fn test_source_identity(
+        #[case] headers: &[(&'static str, &'static str)],
+        #[case] trust_forwarded_headers: bool,
+        #[case] expected: Option<&str>,
+    ) {
+        let headers: HeaderMap = headers
+            .iter()
+            .map(|(name, value)| (name.parse().unwrap(), HeaderValue::from_static(value)))
+            .collect();
+        let peer = Some("192.168.0.1:51234".parse().unwrap());
+        assert_eq!(
+            source_identity(&headers, peer, trust_forwarded_headers).as_deref(),
+            expected
+        );
+    }
+
+    #[test]
+    fn test_source_identity_without_peer_is_unknown() {
+        let mut headers = HeaderMap::new();
+        headers.insert("Authorization", HeaderValue::from_static("GenieKey secret"));
+        headers.insert("X-Real-IP", HeaderValue::from_static("10.0.0.3"));
+        assert_eq!(source_identity(&headers, None, false), None);
+    }
+}
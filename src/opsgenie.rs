@@ -1,18 +1,30 @@
-use crate::config::Config;
+use crate::config::{Config, OpsgenieConfig, UserIdentifierType};
 use crate::opsgenie::error::{
-    NoOnCallPersonSnafu, NoPhoneNumberSnafu, RequestOnCallPersonSnafu,
-    RequestPhoneNumberForPersonSnafu,
+    CheckConnectivitySnafu, ContactsNotExpandedSnafu, CreateAlertSnafu, ListSchedulesSnafu,
+    NoOnCallPersonSnafu, NobodyReachableSnafu, RequestPhoneNumberForPersonSnafu,
+    RequestScheduleMetadataSnafu, RequestTeamMembersSnafu, ScheduleNotFoundSnafu,
+    UnknownScheduleGroupSnafu,
 };
-use crate::util::send_json_request;
+use crate::util::{send_json_request, TtlCache};
 use crate::{http_error, AlertInfo, Schedule};
 use axum::http::{HeaderMap, StatusCode};
 use hyper::header::AUTHORIZATION;
 use reqwest::{Client, Url};
 use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
-use snafu::{OptionExt, ResultExt, Snafu};
+use snafu::{ensure, OptionExt, ResultExt, Snafu};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::sync::Mutex;
+use tokio::sync::Semaphore;
+use urlencoding::encode;
 
 static OPSGENIE_BASEURL: &str = "https://api.opsgenie.com/v2/";
+
+// Number of contact lookups per schedule, contact method and bucketed number of contacts, served
+// on `/metrics`
+static CONTACT_METHOD_COUNTS: Mutex<BTreeMap<(String, &str, &str), u64>> =
+    Mutex::new(BTreeMap::new());
 #[derive(Snafu, Debug)]
 #[snafu(module)]
 pub(crate) enum Error {
@@ -25,8 +37,42 @@ pub(crate) enum Error {
     },
     #[snafu(display("OpsGenie says no one is currently on call!"))]
     NoOnCallPerson {},
-    #[snafu(display("User [{username}] has no phone number configured!"))]
-    NoPhoneNumber { username: String },
+    #[snafu(display("Nobody on call has a phone number, contact lookups: {people}"))]
+    NobodyReachable { people: String },
+    #[snafu(display("No schedule group named [{group}] is configured"))]
+    UnknownScheduleGroup { group: String },
+    #[snafu(display("requesting schedule metadata failed: \n{source}"))]
+    RequestScheduleMetadata { source: crate::util::Error },
+    #[snafu(display("creating alert in OpsGenie failed: \n{source}"))]
+    CreateAlert { source: crate::util::Error },
+    #[snafu(display("listing schedules failed: \n{source}"))]
+    ListSchedules { source: crate::util::Error },
+    #[snafu(display("OpsGenie is not reachable: \n{source}"))]
+    CheckConnectivity { source: crate::util::Error },
+    #[snafu(display(
+        "OpsGenie did not return the contacts of [{username}], check the permissions of the API key"
+    ))]
+    ContactsNotExpanded { username: String },
+    #[snafu(display("requesting members of team [{team}] failed: \n{source}"))]
+    RequestTeamMembers {
+        source: crate::util::Error,
+        team: String,
+    },
+    #[snafu(display("No schedule [{schedule}] exists in OpsGenie"))]
+    ScheduleNotFound { schedule: String },
+}
+
+impl Error {
+    /// True if OpsGenie told us that the requested schedule does not exist
+    pub fn is_schedule_not_found(&self) -> bool {
+        match self {
+            Error::RequestOnCallPerson { source } | Error::RequestScheduleMetadata { source } => {
+                source.is_not_found()
+            }
+            Error::ScheduleNotFound { .. } => true,
+            _ => false,
+        }
+    }
 }
 
 impl http_error::Error for Error {
@@ -34,8 +80,16 @@ impl http_error::Error for Error {
         match self {
             Error::RequestOnCallPerson { .. } => StatusCode::UNPROCESSABLE_ENTITY,
             Error::NoOnCallPerson { .. } => StatusCode::IM_A_TEAPOT,
-            Error::NoPhoneNumber { .. } => StatusCode::IM_A_TEAPOT,
+            Error::NobodyReachable { .. } => StatusCode::IM_A_TEAPOT,
             Error::RequestPhoneNumberForPerson { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::UnknownScheduleGroup { .. } => StatusCode::NOT_FOUND,
+            Error::RequestScheduleMetadata { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::CreateAlert { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::ListSchedules { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::CheckConnectivity { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::RequestTeamMembers { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::ContactsNotExpanded { .. } => StatusCode::BAD_GATEWAY,
+            Error::ScheduleNotFound { .. } => StatusCode::NOT_FOUND,
         }
     }
 }
@@ -45,6 +99,64 @@ impl http_error::Error for Error {
 pub struct UserPhoneNumber {
     pub name: String,
     pub phone: Vec<String>,
+    // Explains why `phone` is empty
+    pub status: ContactStatus,
+    // Only set when the request was for a schedule group
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tier: Option<ScheduleTier>,
+    // Set when the person is on call because they are a member of this team
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub team: Option<String>,
+    // Contact methods each number in `phone` is registered for
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub capabilities: PhoneCapabilities,
+}
+
+/// Phone numbers of a person with the contact methods they are registered for, a number that is
+/// registered for voice and sms is only listed once
+pub type PhoneCapabilities = BTreeMap<String, BTreeSet<ContactMethod>>;
+
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ContactMethod {
+    Voice,
+    Sms,
+}
+
+impl UserPhoneNumber {
+    /// The number this person is reached at when only one number is used
+    pub fn primary_number(&self) -> Option<&String> {
+        self.phone.first()
+    }
+}
+
+/// Outcome of looking up the contact information of a person that is on call
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum ContactStatus {
+    Ok,
+    NoContacts,
+    LookupFailed,
+    // OpsGenie returned the user without their contacts, usually a permission problem
+    ContactsNotExpanded,
+}
+
+impl ContactStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContactStatus::Ok => "ok",
+            ContactStatus::NoContacts => "no_contacts",
+            ContactStatus::LookupFailed => "lookup_failed",
+            ContactStatus::ContactsNotExpanded => "contacts_not_expanded",
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub enum ScheduleTier {
+    Primary,
+    Backup,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -56,33 +168,303 @@ struct OnCallResult {
 #[derive(Clone, Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 struct OnCallResultData {
+    #[serde(rename = "_parent")]
+    parent: Option<OnCallParent>,
     on_call_recipients: Vec<String>,
 }
 
+// The schedule the on call information belongs to, also when it was requested by name
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OnCallParent {
+    id: String,
+}
+
+// Non-flat version of the on call response, this is only requested when we need more information
+// about the participants than just their names (e.g. to filter on their role)
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DetailedOnCallResult {
+    data: DetailedOnCallResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct DetailedOnCallResultData {
+    #[serde(rename = "_parent")]
+    parent: Option<OnCallParent>,
+    #[serde(default)]
+    on_call_participants: Vec<OnCallParticipant>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct OnCallParticipant {
+    name: String,
+    #[serde(rename = "type")]
+    participant_type: String,
+    role: Option<String>,
+    #[serde(default)]
+    on_call_participants: Vec<OnCallParticipant>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleResult {
+    data: ScheduleResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleResultData {
+    id: String,
+    name: String,
+    timezone: Option<String>,
+    enabled: Option<bool>,
+    #[serde(default)]
+    rotations: Vec<Rotation>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Rotation {
+    name: String,
+}
+
+/// Slowly changing information about a schedule, returned when detailed information is requested
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleMetadata {
+    pub id: String,
+    pub name: String,
+    pub timezone: Option<String>,
+    pub enabled: Option<bool>,
+    pub rotations: Vec<String>,
+}
+
+/// Caches schedule metadata so that we don't have to ask OpsGenie for it on every request.
+/// Entries are refreshed after `metadata_refresh_interval` has passed, which is independent of the
+/// on call information, which is never cached.
+pub type ScheduleMetadataCache = TtlCache<Schedule, ScheduleMetadata>;
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct ScheduleListResult {
+    #[serde(default)]
+    data: Vec<ScheduleListEntry>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleListEntry {
+    pub id: String,
+    pub name: String,
+}
+
+/// One page of the schedules that exist in OpsGenie
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleList {
+    pub schedules: Vec<ScheduleListEntry>,
+    pub offset: usize,
+    pub limit: usize,
+    pub total: usize,
+    pub has_more: bool,
+}
+
 pub fn get_base_url() -> Result<Url, url::ParseError> {
     Url::parse(OPSGENIE_BASEURL)
 }
 
+/// Returns metadata for the requested schedule, served from the cache if possible.
+/// Schedule groups consist of more than one schedule, so no metadata is returned for them.
+pub(crate) async fn get_schedule_metadata(
+    schedule: &Schedule,
+    http: &Client,
+    opsgenie_config: &OpsgenieConfig,
+    cache: &ScheduleMetadataCache,
+) -> Result<Option<ScheduleMetadata>, Error> {
+    let (schedule_identifier, schedule_identifier_type) = match schedule {
+        Schedule::ScheduleById(id) => (&id.id, "id"),
+        Schedule::ScheduleByName(name) => (&name.name, "name"),
+        Schedule::ScheduleByGroup(_) => return Ok(None),
+    };
+
+    // Concurrent requests for the same schedule on a cold cache only fetch the metadata once
+    let metadata = cache
+        .get_or_try_insert_with(
+            schedule.clone(),
+            opsgenie_config.metadata_refresh_interval,
+            move || async move {
+                let url_builder = opsgenie_config
+                    .base_url
+                    .join(&format!("schedules/{}", encode(schedule_identifier)))
+                    .unwrap();
+                tracing::debug!(
+                    "Retrieving schedule metadata from [{}]",
+                    url_builder.to_string()
+                );
+
+                let mut outgoing_headers = HeaderMap::new();
+                outgoing_headers.insert(
+                    AUTHORIZATION,
+                    opsgenie_config.credentials.expose_secret().clone().0,
+                );
+
+                let result = send_json_request::<ScheduleResult>(
+                    http.get(url_builder)
+                        .headers(outgoing_headers)
+                        .query(&opsgenie_config.extra_query_params)
+                        .query(&[("identifierType", schedule_identifier_type)]),
+                )
+                .await
+                .inspect_err(|e| {
+                    if e.is_not_found() {
+                        tracing::debug!(?schedule, "Invalidating cached schedule metadata");
+                        cache.invalidate(schedule);
+                    }
+                })
+                .context(RequestScheduleMetadataSnafu)?;
+
+                Ok::<_, Error>(ScheduleMetadata {
+                    id: result.data.id,
+                    name: result.data.name,
+                    timezone: result.data.timezone,
+                    enabled: result.data.enabled,
+                    rotations: result
+                        .data
+                        .rotations
+                        .into_iter()
+                        .map(|rotation| rotation.name)
+                        .collect(),
+                })
+            },
+        )
+        .await?;
+
+    Ok(Some(metadata))
+}
+
 pub(crate) async fn get_oncall_number(
     schedule: &Schedule,
     http: &Client,
     config: &Config,
+    upstream_permits: &Semaphore,
 ) -> Result<AlertInfo, Error> {
     let Config {
         opsgenie_config,
         slack_config,
         ..
     } = config;
-    let mut url_builder = opsgenie_config.base_url.clone();
 
-    let (schedule_identifier, schedule_identifier_type) = match schedule {
-        Schedule::ScheduleById(id) => (&id.id, "id"),
-        Schedule::ScheduleByName(name) => (&name.name, "name"),
+    let mut outgoing_headers = HeaderMap::new();
+    outgoing_headers.insert(
+        AUTHORIZATION,
+        opsgenie_config.credentials.expose_secret().clone().0,
+    );
+
+    // OpsGenie returns the id of the schedule along with the on call information, so callers that
+    // asked by name learn the id for later requests without an extra lookup
+    let (result_list, schedule_id) = match schedule {
+        Schedule::ScheduleById(id) => {
+            let (users, schedule_id) = get_schedule_users(
+                &id.id,
+                "id",
+                http,
+                opsgenie_config,
+                &outgoing_headers,
+                upstream_permits,
+            )
+            .await?;
+            (users, schedule_id.or_else(|| Some(id.id.clone())))
+        }
+        Schedule::ScheduleByName(name) => {
+            get_schedule_users(
+                &name.name,
+                "name",
+                http,
+                opsgenie_config,
+                &outgoing_headers,
+                upstream_permits,
+            )
+            .await?
+        }
+        Schedule::ScheduleByGroup(group) => {
+            let schedule_group = opsgenie_config.schedule_groups.get(&group.group).context(
+                UnknownScheduleGroupSnafu {
+                    group: &group.group,
+                },
+            )?;
+            tracing::debug!(
+                ?schedule_group,
+                "Resolving on call persons for schedule group"
+            );
+
+            let (primary, _) = get_schedule_users(
+                &schedule_group.primary,
+                "name",
+                http,
+                opsgenie_config,
+                &outgoing_headers,
+                upstream_permits,
+            )
+            .await?;
+            let (backup, _) = get_schedule_users(
+                &schedule_group.backup,
+                "name",
+                http,
+                opsgenie_config,
+                &outgoing_headers,
+                upstream_permits,
+            )
+            .await?;
+            (merge_schedule_tiers(primary, backup), None)
+        }
     };
 
-    url_builder = url_builder
-        .join(&format!("schedules/{schedule_identifier}/on-calls"))
-        .unwrap();
+    let (username, phone_number) = first_reachable_person(&result_list)?;
+
+    Ok(AlertInfo {
+        username,
+        phone_number,
+        full_information: result_list,
+        full_information_truncated: false,
+        schedule_id,
+        schedule: None,
+    })
+}
+
+/// Picks the person that is returned as `username` and `phoneNumber`, which is the first person on
+/// call that has a number. Everybody else is still part of the full list with their contact
+/// status, so one person whose contacts couldn't be looked up doesn't fail the whole request.
+/// If nobody has a number the error lists why for every person, so a permission problem isn't
+/// reported as people not having configured a number.
+fn first_reachable_person(users: &[UserPhoneNumber]) -> Result<(String, String), Error> {
+    ensure!(!users.is_empty(), NoOnCallPersonSnafu);
+    users
+        .iter()
+        .find_map(|user| {
+            user.primary_number()
+                .map(|number| (user.name.clone(), number.clone()))
+        })
+        .with_context(|| NobodyReachableSnafu {
+            people: users
+                .iter()
+                .map(|user| format!("[{}] {}", user.name, user.status.as_str()))
+                .collect::<Vec<_>>()
+                .join(", "),
+        })
+}
+
+// We only care about whether the request succeeds, not about the account details
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct AccountResult {}
+
+/// Checks that OpsGenie is reachable and accepts our credentials
+pub(crate) async fn check_connectivity(http: &Client, config: &Config) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url_builder = opsgenie_config.base_url.join("account").unwrap();
 
     let mut outgoing_headers = HeaderMap::new();
     outgoing_headers.insert(
@@ -90,61 +472,466 @@ pub(crate) async fn get_oncall_number(
         opsgenie_config.credentials.expose_secret().clone().0,
     );
 
+    send_json_request::<AccountResult>(http.get(url_builder).headers(outgoing_headers))
+        .await
+        .context(CheckConnectivitySnafu)?;
+    Ok(())
+}
+
+/// Returns the requested page of all schedules, `limit` is capped at the configured maximum to
+/// keep responses for accounts with lots of schedules at a reasonable size.
+/// OpsGenie returns all schedules at once, so paging happens on our side.
+pub(crate) async fn list_schedules(
+    offset: usize,
+    limit: usize,
+    http: &Client,
+    config: &Config,
+) -> Result<ScheduleList, Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url_builder = opsgenie_config.base_url.join("schedules").unwrap();
+    tracing::debug!("Listing schedules from [{}]", url_builder.to_string());
+
+    let mut outgoing_headers = HeaderMap::new();
+    outgoing_headers.insert(
+        AUTHORIZATION,
+        opsgenie_config.credentials.expose_secret().clone().0,
+    );
+
+    let result =
+        send_json_request::<ScheduleListResult>(http.get(url_builder).headers(outgoing_headers))
+            .await
+            .context(ListSchedulesSnafu)?;
+
+    let limit = limit.min(opsgenie_config.max_schedules_per_page);
+    let total = result.data.len();
+    let schedules: Vec<ScheduleListEntry> =
+        result.data.into_iter().skip(offset).take(limit).collect();
+
+    Ok(ScheduleList {
+        has_more: offset.saturating_add(schedules.len()) < total,
+        schedules,
+        offset,
+        limit,
+        total,
+    })
+}
+
+/// Retrieves everybody who is currently on call for a single schedule, including their phone
+/// numbers, and the id of the schedule if OpsGenie returned it.
+/// An empty list is returned if nobody is on call, it is up to the caller to decide whether that
+/// is an error.
+async fn get_schedule_users(
+    schedule_identifier: &str,
+    schedule_identifier_type: &str,
+    http: &Client,
+    opsgenie_config: &OpsgenieConfig,
+    outgoing_headers: &HeaderMap,
+    upstream_permits: &Semaphore,
+) -> Result<(Vec<UserPhoneNumber>, Option<String>), Error> {
+    // Schedule names can contain characters that have a meaning in urls
+    let url_builder = opsgenie_config
+        .base_url
+        .join(&format!(
+            "schedules/{}/on-calls",
+            encode(schedule_identifier)
+        ))
+        .unwrap();
+
     tracing::debug!(
         "Retrieving on call person from [{}]",
         url_builder.to_string()
     );
     tracing::debug!("Using headers: [{:?}]", outgoing_headers);
 
-    let persons_on_call = send_json_request::<OnCallResult>(
-        http.get(url_builder.clone())
-            .headers(outgoing_headers.clone())
-            .query(&[
+    let request = http
+        .get(url_builder.clone())
+        .headers(outgoing_headers.clone())
+        .query(&opsgenie_config.extra_query_params);
+
+    // The flat response only contains names, so if we need to filter on roles or expand teams we
+    // have to ask for the detailed response instead
+    let (on_call_recipients, schedule_id): (Vec<Recipient>, Option<String>) =
+        if opsgenie_config.role_filter.is_empty() && !opsgenie_config.expand_teams {
+            let persons_on_call = send_json_request::<OnCallResult>(request.query(&[
                 ("flat", "true"),
                 ("scheduleIdentifierType", schedule_identifier_type),
-            ]),
-    )
-    .await
-    .context(RequestOnCallPersonSnafu)?;
+            ]))
+            .await
+            .map_err(|source| on_call_request_error(source, schedule_identifier))?
+            .data;
+            (
+                persons_on_call
+                    .on_call_recipients
+                    .into_iter()
+                    .map(Recipient::User)
+                    .collect(),
+                persons_on_call.parent.map(|parent| parent.id),
+            )
+        } else {
+            let persons_on_call = send_json_request::<DetailedOnCallResult>(request.query(&[
+                ("flat", "false"),
+                ("scheduleIdentifierType", schedule_identifier_type),
+            ]))
+            .await
+            .map_err(|source| on_call_request_error(source, schedule_identifier))?;
+            let recipients = collect_recipients(
+                &persons_on_call.data.on_call_participants,
+                &opsgenie_config.role_filter,
+            );
+            tracing::debug!(
+                ?recipients,
+                role_filter = ?opsgenie_config.role_filter,
+                "Collected on call participants"
+            );
+            (
+                recipients,
+                persons_on_call.data.parent.map(|parent| parent.id),
+            )
+        };
 
-    // We don't need this value, this is just to check the response wasn't empty and no one is
-    // on call
-    persons_on_call
-        .data
-        .on_call_recipients
-        .get(0)
-        .context(NoOnCallPersonSnafu)?;
+    // Resolve teams into their members, every entry is the username and the team it came from
+    let mut on_call_users: Vec<(String, Option<String>)> = Vec::new();
+    for recipient in on_call_recipients {
+        match recipient {
+            Recipient::User(user) => {
+                if !on_call_users.iter().any(|(existing, _)| existing.eq(&user)) {
+                    on_call_users.push((user, None));
+                }
+            }
+            Recipient::Team(team) if opsgenie_config.expand_teams => {
+                let members = get_team_members(
+                    http,
+                    opsgenie_config,
+                    outgoing_headers,
+                    &team,
+                    upstream_permits,
+                )
+                .await?;
+                tracing::info!(team, ?members, "Expanding on call team into its members");
+                for member in members {
+                    if !on_call_users
+                        .iter()
+                        .any(|(existing, _)| existing.eq(&member))
+                    {
+                        on_call_users.push((member, Some(team.clone())));
+                    }
+                }
+            }
+            Recipient::Team(team) => {
+                tracing::warn!(
+                    team,
+                    "Team is on call, but expanding teams is disabled, nobody from this team will be alerted"
+                );
+            }
+        }
+    }
+
+    // OpsGenie doesn't document an order for on call recipients, deployments that don't rely on it
+    // get a stable order instead, which also decides who is returned as the first person
+    if !opsgenie_config.preserve_recipient_order {
+        on_call_users.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
 
     let mut result_list: Vec<UserPhoneNumber> = Vec::new();
 
-    for user in persons_on_call.data.on_call_recipients {
+    for (user, team) in on_call_users {
         tracing::debug!(user, "Looking up phone number");
-        let phone_number = get_phone_number(
+        // Shared with the Twilio calls, this bounds the total work we have in flight upstream
+        let _permit = upstream_permits
+            .acquire()
+            .await
+            .expect("upstream request semaphore is never closed");
+        let (capabilities, status) = match get_phone_number(
             http.clone(),
             opsgenie_config.base_url.clone(),
-            &outgoing_headers,
+            outgoing_headers,
             &user,
+            opsgenie_config.user_identifier_type,
+            schedule_identifier,
         )
         .await
-        .context(RequestPhoneNumberForPersonSnafu { username: &user })?;
+        .context(RequestPhoneNumberForPersonSnafu { username: &user })
+        {
+            Ok(Some(numbers)) if numbers.is_empty() => (numbers, ContactStatus::NoContacts),
+            Ok(Some(numbers)) => (numbers, ContactStatus::Ok),
+            Ok(None) => {
+                ensure!(
+                    !opsgenie_config.fail_on_unexpanded_contacts,
+                    ContactsNotExpandedSnafu { username: &user }
+                );
+                tracing::warn!(
+                    user,
+                    "OpsGenie did not return the contacts of the user, the API key might lack the permission to read them"
+                );
+                (PhoneCapabilities::new(), ContactStatus::ContactsNotExpanded)
+            }
+            // One unreachable contact lookup should not keep us from alerting everybody else
+            Err(error) => {
+                tracing::warn!(
+                    error = &error as &dyn std::error::Error,
+                    user,
+                    "Looking up contact information failed"
+                );
+                (PhoneCapabilities::new(), ContactStatus::LookupFailed)
+            }
+        };
         result_list.push(UserPhoneNumber {
             name: user.to_string(),
-            phone: phone_number,
+            phone: capabilities.keys().cloned().collect(),
+            status,
+            tier: None,
+            team,
+            capabilities,
         })
     }
 
-    let user = result_list.get(0).context(NoOnCallPersonSnafu)?;
-    let username = &user.name;
-    let phone_number = user
-        .phone
-        .get(0)
-        .context(NoPhoneNumberSnafu { username: username })?;
+    Ok((result_list, schedule_id))
+}
 
-    Ok(AlertInfo {
-        username: username.clone(),
-        phone_number: phone_number.clone(),
-        full_information: result_list,
-    })
+/// OpsGenie answers 404 if no schedule with the requested name or id exists, this is reported with
+/// the schedule that was asked for instead of as a failed request.
+/// Schedule names are unique in OpsGenie, so a name can't match more than one schedule.
+fn on_call_request_error(source: crate::util::Error, schedule_identifier: &str) -> Error {
+    if source.is_not_found() {
+        ScheduleNotFoundSnafu {
+            schedule: schedule_identifier,
+        }
+        .build()
+    } else {
+        Error::RequestOnCallPerson { source }
+    }
+}
+
+/// Labels the persons from a primary and backup schedule with their tier and combines them into
+/// one list.
+/// People that are on call on both schedules are only listed once, as part of the primary tier.
+fn merge_schedule_tiers(
+    primary: Vec<UserPhoneNumber>,
+    backup: Vec<UserPhoneNumber>,
+) -> Vec<UserPhoneNumber> {
+    let mut result_list: Vec<UserPhoneNumber> = primary
+        .into_iter()
+        .map(|user| UserPhoneNumber {
+            tier: Some(ScheduleTier::Primary),
+            ..user
+        })
+        .collect();
+
+    for user in backup {
+        if result_list
+            .iter()
+            .any(|existing| existing.name == user.name)
+        {
+            tracing::debug!(
+                user.name,
+                "Person is on call for primary and backup schedule, only alerting once"
+            );
+            continue;
+        }
+        result_list.push(UserPhoneNumber {
+            tier: Some(ScheduleTier::Backup),
+            ..user
+        });
+    }
+    result_list
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Recipient {
+    User(String),
+    Team(String),
+}
+
+/// Walks the (possibly nested) participants and returns all users and teams that have one of the
+/// allowed roles, in the order in which OpsGenie returned them.
+/// If no roles are given all users and teams are returned.
+fn collect_recipients(participants: &[OnCallParticipant], roles: &[String]) -> Vec<Recipient> {
+    let mut result = Vec::new();
+    for participant in participants {
+        let role_matches = roles.is_empty()
+            || participant.role.as_ref().is_some_and(|participant_role| {
+                roles
+                    .iter()
+                    .any(|role| role.eq_ignore_ascii_case(participant_role))
+            });
+        let recipient = match participant.participant_type.as_str() {
+            "user" => Some(Recipient::User(participant.name.clone())),
+            "team" => Some(Recipient::Team(participant.name.clone())),
+            _ => None,
+        };
+        if let Some(recipient) = recipient {
+            if role_matches && !result.contains(&recipient) {
+                result.push(recipient);
+            }
+        }
+        for nested in collect_recipients(&participant.on_call_participants, roles) {
+            if !result.contains(&nested) {
+                result.push(nested);
+            }
+        }
+    }
+    result
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TeamResult {
+    data: TeamResultData,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TeamResultData {
+    #[serde(default)]
+    members: Vec<TeamMember>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TeamMember {
+    user: TeamMemberUser,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TeamMemberUser {
+    username: String,
+}
+
+/// Returns the usernames of the members of a team, capped at the configured maximum team size so
+/// a large team being on call can't result in a mass dial
+async fn get_team_members(
+    http: &Client,
+    opsgenie_config: &OpsgenieConfig,
+    outgoing_headers: &HeaderMap,
+    team: &str,
+    upstream_permits: &Semaphore,
+) -> Result<Vec<String>, Error> {
+    // Team names can contain characters that have a meaning in urls
+    let url_builder = opsgenie_config
+        .base_url
+        .join(&format!("teams/{}", encode(team)))
+        .unwrap();
+    tracing::debug!(
+        "Retrieving members of team [{}] from [{}]",
+        team,
+        url_builder.to_string()
+    );
+
+    let _permit = upstream_permits
+        .acquire()
+        .await
+        .expect("upstream request semaphore is never closed");
+    let result = send_json_request::<TeamResult>(
+        http.get(url_builder)
+            .headers(outgoing_headers.clone())
+            .query(&opsgenie_config.extra_query_params)
+            .query(&[("identifierType", "name")]),
+    )
+    .await
+    .context(RequestTeamMembersSnafu { team })?;
+
+    let mut members: Vec<String> = result
+        .data
+        .members
+        .into_iter()
+        .map(|member| member.user.username)
+        .collect();
+    if members.len() > opsgenie_config.max_team_size {
+        tracing::warn!(
+            team,
+            members = members.len(),
+            max_team_size = opsgenie_config.max_team_size,
+            "Team has more members than allowed, only alerting the first ones"
+        );
+        members.truncate(opsgenie_config.max_team_size);
+    }
+    Ok(members)
+}
+
+#[derive(Clone, Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CreateAlertRequest {
+    message: String,
+    description: String,
+    // Alerts with the same alias are deduplicated by OpsGenie while the first one is open
+    #[serde(skip_serializing_if = "Option::is_none")]
+    alias: Option<String>,
+    priority: String,
+    tags: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct CreateAlertResult {
+    request_id: String,
+}
+
+/// Creates an OpsGenie alert when nobody could be reached, so that the failed page ends up as
+/// something that is tracked instead of only being an error response to whoever called us.
+pub(crate) async fn create_total_failure_alert(
+    schedule: &Schedule,
+    failure_details: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let alert = CreateAlertRequest {
+        message: format!("Nobody on call could be reached for schedule {schedule}"),
+        description: format!(
+            "All attempts to alert the people on call for schedule {schedule} failed:\n{failure_details}"
+        ),
+        alias: None,
+        priority: config.opsgenie_config.alert_priority.clone(),
+        tags: vec![crate::APP_NAME.to_string()],
+    };
+    tracing::warn!(?alert, "Nobody could be reached, creating OpsGenie alert");
+    create_alert(&alert, http, config).await
+}
+
+/// Creates an OpsGenie alert when alerts are refused because the paging budget is used up, this
+/// either means something is looping or there is a real incident that needs more calls than
+/// expected, both need somebody to look at it.
+pub(crate) async fn create_paging_budget_alert(
+    schedule: &Schedule,
+    budget_details: &str,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let alert = CreateAlertRequest {
+        message: "Global paging budget exceeded, alerts are being refused".to_string(),
+        description: format!(
+            "The alert for schedule {schedule} was refused because {budget_details}. No calls are placed until calls from the current window expire."
+        ),
+        alias: Some(format!("{}-paging-budget-exceeded", crate::APP_NAME)),
+        priority: config.opsgenie_config.alert_priority.clone(),
+        tags: vec![crate::APP_NAME.to_string()],
+    };
+    tracing::error!(?alert, "Paging budget exceeded, creating OpsGenie alert");
+    create_alert(&alert, http, config).await
+}
+
+async fn create_alert(
+    alert: &CreateAlertRequest,
+    http: &Client,
+    config: &Config,
+) -> Result<(), Error> {
+    let opsgenie_config = &config.opsgenie_config;
+    let url_builder = opsgenie_config.base_url.join("alerts").unwrap();
+
+    let mut outgoing_headers = HeaderMap::new();
+    outgoing_headers.insert(
+        AUTHORIZATION,
+        opsgenie_config.credentials.expose_secret().clone().0,
+    );
+
+    let result = send_json_request::<CreateAlertResult>(
+        http.post(url_builder).headers(outgoing_headers).json(alert),
+    )
+    .await
+    .context(CreateAlertSnafu)?;
+    tracing::info!(result.request_id, "OpsGenie accepted alert");
+
+    Ok(())
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -159,7 +946,8 @@ struct ContactInformationResultData {
     id: String,
     username: String,
     full_name: String,
-    user_contacts: Vec<UserContact>,
+    // Missing if the contacts were not expanded, not to be confused with an empty list
+    user_contacts: Option<Vec<UserContact>>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
@@ -176,7 +964,9 @@ async fn get_phone_number(
     base_url: Url,
     headers: &HeaderMap,
     username: &str,
-) -> Result<Vec<String>, crate::util::Error> {
+    identifier_type: UserIdentifierType,
+    schedule: &str,
+) -> Result<Option<PhoneCapabilities>, crate::util::Error> {
     let url_builder = base_url.clone();
     let url_builder = url_builder.join(&format!("users/{username}")).unwrap();
     tracing::debug!(
@@ -185,32 +975,683 @@ async fn get_phone_number(
         url_builder.to_string()
     );
     tracing::debug!("Using headers: [{:?}]", headers);
-    let contact_information = send_json_request::<ContactInformationResult>(
-        http.get(url_builder.clone())
-            .headers(headers.clone())
-            .query(&[("expand", "contact")]),
-    )
-    .await?;
+    let request = http
+        .get(url_builder.clone())
+        .headers(headers.clone())
+        .query(&[("expand", "contact")]);
+    let contact_information = match send_json_request::<ContactInformationResult>(request).await {
+        // Some accounts don't resolve the identifier without being told what kind it is
+        Err(e) if e.is_not_found() => {
+            tracing::debug!(
+                username,
+                identifier_type = identifier_type.as_str(),
+                "User not found, retrying with explicit identifier type"
+            );
+            send_json_request::<ContactInformationResult>(
+                http.get(url_builder).headers(headers.clone()).query(&[
+                    ("expand", "contact"),
+                    ("identifierType", identifier_type.as_str()),
+                ]),
+            )
+            .await?
+        }
+        result => result?,
+    };
     tracing::trace!("Got data from opsgenie: [{:?}]", contact_information);
 
-    let mut numbers = contact_information
-        .data
-        .user_contacts
-        .iter()
-        .filter(|user_contact| {
-            user_contact.contact_method.eq("voice") || user_contact.contact_method.eq("sms")
-        })
-        .map(|user_contact| format_phone_number(user_contact.to.clone()))
-        .collect::<Vec<String>>();
+    if let Some(counts) = count_contact_methods(&contact_information.data) {
+        counts.record(schedule);
+    }
+    Ok(extract_phone_numbers(&contact_information.data))
+}
 
-    // Sort to enable easier deduplication and remove duplicate numbers
-    numbers.sort();
-    numbers.dedup();
+/// How many contacts of each kind a person has, to spot people (or whole schedules) with few ways
+/// to reach them
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct ContactMethodCounts {
+    voice: usize,
+    sms: usize,
+    // Contacts we don't use for alerting, like email
+    filtered: usize,
+}
 
-    Ok(numbers)
+impl ContactMethodCounts {
+    /// Counts the lookup for the schedule, once per contact method. The number of contacts is
+    /// bucketed so the number of distinct counters stays small.
+    fn record(&self, schedule: &str) {
+        tracing::debug!(
+            schedule,
+            voice_contacts = contact_count_bucket(self.voice),
+            sms_contacts = contact_count_bucket(self.sms),
+            filtered_contacts = contact_count_bucket(self.filtered),
+            "Counted contact methods"
+        );
+        let mut counters = CONTACT_METHOD_COUNTS
+            .lock()
+            .expect("contact method counters are never poisoned");
+        for (method, count) in [
+            ("voice", self.voice),
+            ("sms", self.sms),
+            ("filtered", self.filtered),
+        ] {
+            *counters
+                .entry((schedule.to_string(), method, contact_count_bucket(count)))
+                .or_default() += 1;
+        }
+    }
+}
+
+/// Renders the contact method counters in the Prometheus text format
+pub fn contact_method_metrics() -> String {
+    let counters = CONTACT_METHOD_COUNTS
+        .lock()
+        .expect("contact method counters are never poisoned");
+    let mut metrics = String::from(
+        "# HELP wygc_contact_methods_total Contact lookups by schedule, contact method and number of contacts of that method.\n\
+         # TYPE wygc_contact_methods_total counter\n",
+    );
+    for ((schedule, method, bucket), value) in counters.iter() {
+        // Schedule names are free text, label values have to escape these characters
+        let schedule = schedule
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+        writeln!(
+            metrics,
+            "wygc_contact_methods_total{{schedule=\"{schedule}\",method=\"{method}\",contacts=\"{bucket}\"}} {value}"
+        )
+        .expect("writing to a string never fails");
+    }
+    metrics
+}
+
+/// Returns `None` if OpsGenie did not expand the contacts, there is nothing to count then
+fn count_contact_methods(
+    contact_information: &ContactInformationResultData,
+) -> Option<ContactMethodCounts> {
+    let mut counts = ContactMethodCounts {
+        voice: 0,
+        sms: 0,
+        filtered: 0,
+    };
+    for user_contact in contact_information.user_contacts.as_ref()? {
+        match user_contact.contact_method.as_str() {
+            "voice" => counts.voice += 1,
+            "sms" => counts.sms += 1,
+            _ => counts.filtered += 1,
+        }
+    }
+    Some(counts)
+}
+
+fn contact_count_bucket(count: usize) -> &'static str {
+    match count {
+        0 => "0",
+        1 => "1",
+        2 => "2",
+        _ => "3+",
+    }
+}
+
+/// Returns the phone numbers of a user, or `None` if OpsGenie did not expand the contacts (e.g.
+/// because the API key is not allowed to read them), which is different from the user having no
+/// contacts at all.
+/// Numbers that are registered more than once (e.g. for voice and sms) are merged, keeping all
+/// of their contact methods.
+fn extract_phone_numbers(
+    contact_information: &ContactInformationResultData,
+) -> Option<PhoneCapabilities> {
+    let mut numbers = PhoneCapabilities::new();
+    for user_contact in contact_information.user_contacts.as_ref()? {
+        let method = match user_contact.contact_method.as_str() {
+            "voice" => ContactMethod::Voice,
+            "sms" => ContactMethod::Sms,
+            _ => continue,
+        };
+        numbers
+            .entry(format_phone_number(user_contact.to.clone()))
+            .or_default()
+            .insert(method);
+    }
+    Some(numbers)
 }
 
 fn format_phone_number(number: String) -> String {
     let number = number.replace("-", "");
     format!("+{}", number)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{
+        collect_recipients, contact_count_bucket, contact_method_metrics, count_contact_methods,
+        extract_phone_numbers, first_reachable_person, get_phone_number, get_schedule_metadata,
+        get_schedule_users, get_team_members, merge_schedule_tiers, ContactInformationResult,
+        ContactMethod, ContactMethodCounts, ContactStatus, DetailedOnCallResult, Error,
+        OnCallParticipant, OnCallResult, PhoneCapabilities, Recipient, ScheduleMetadataCache,
+        ScheduleTier, UserPhoneNumber,
+    };
+    use crate::config::{AuthHeader, OpsgenieConfig, UserIdentifierType};
+    use crate::http_error::Error as _;
+    use crate::{Schedule, ScheduleRequestByName};
+    use axum::extract::{Path, Query};
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+    use axum::response::IntoResponse;
+    use axum::routing::get;
+    use axum::{Json, Router};
+    use reqwest::{Client, Url};
+    use rstest::rstest;
+    use secrecy::Secret;
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+
+    fn opsgenie_config(base_url: Url) -> OpsgenieConfig {
+        OpsgenieConfig {
+            base_url,
+            credentials: Secret::new(AuthHeader(HeaderValue::from_static("GenieKey test"))),
+            role_filter: vec![],
+            preserve_recipient_order: true,
+            schedule_groups: HashMap::new(),
+            expand_teams: false,
+            max_team_size: 50,
+            fail_on_unexpanded_contacts: false,
+            extra_query_params: vec![],
+            alert_on_total_failure: false,
+            alert_priority: "P1".to_string(),
+            max_schedules_per_page: 100,
+            metadata_refresh_interval: Duration::from_secs(3600),
+            user_identifier_type: UserIdentifierType::Username,
+        }
+    }
+
+    /// Serves the on call endpoint of a fake OpsGenie that only knows the schedule `ops`
+    async fn serve_on_calls() -> Url {
+        let app = Router::new().route(
+            "/schedules/:schedule/on-calls",
+            get(|Path(schedule): Path<String>| async move {
+                if schedule == "ops" {
+                    Json(serde_json::json!({"data": {
+                        "_parent": {"id": "d875e654", "name": "ops", "enabled": true},
+                        "onCallRecipients": []
+                    }}))
+                    .into_response()
+                } else {
+                    StatusCode::NOT_FOUND.into_response()
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        base_url
+    }
+
+    fn participant(name: &str, participant_type: &str, role: Option<&str>) -> OnCallParticipant {
+        OnCallParticipant {
+            name: name.to_string(),
+            participant_type: participant_type.to_string(),
+            role: role.map(String::from),
+            on_call_participants: vec![],
+        }
+    }
+
+    #[test]
+    fn test_collect_recipients_with_role() {
+        let mut escalation = participant("escalation", "escalation", None);
+        escalation.on_call_participants = vec![
+            participant("primary@example.com", "user", Some("Primary")),
+            participant("observer@example.com", "user", Some("Observer")),
+        ];
+        let participants = vec![
+            participant("other-primary@example.com", "user", Some("primary")),
+            participant("no-role@example.com", "user", None),
+            participant("primary-team", "team", Some("Primary")),
+            escalation,
+        ];
+
+        assert_eq!(
+            collect_recipients(&participants, &["Primary".to_string()]),
+            vec![
+                Recipient::User("other-primary@example.com".to_string()),
+                Recipient::Team("primary-team".to_string()),
+                Recipient::User("primary@example.com".to_string()),
+            ]
+        );
+        assert_eq!(
+            collect_recipients(&participants, &[]),
+            vec![
+                Recipient::User("other-primary@example.com".to_string()),
+                Recipient::User("no-role@example.com".to_string()),
+                Recipient::Team("primary-team".to_string()),
+                Recipient::User("primary@example.com".to_string()),
+                Recipient::User("observer@example.com".to_string()),
+            ]
+        );
+    }
+
+    fn user(name: &str, tier: Option<ScheduleTier>) -> UserPhoneNumber {
+        UserPhoneNumber {
+            name: name.to_string(),
+            phone: vec![format!("+49{}", name.len())],
+            status: ContactStatus::Ok,
+            tier,
+            team: None,
+            capabilities: PhoneCapabilities::new(),
+        }
+    }
+
+    #[test]
+    fn test_merge_schedule_tiers() {
+        let merged = merge_schedule_tiers(
+            vec![user("alice", None), user("bob", None)],
+            vec![user("bob", None), user("carol", None)],
+        );
+
+        assert_eq!(
+            merged,
+            vec![
+                user("alice", Some(ScheduleTier::Primary)),
+                user("bob", Some(ScheduleTier::Primary)),
+                user("carol", Some(ScheduleTier::Backup)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_on_call_results_carry_schedule_id() {
+        let flat: OnCallResult = serde_json::from_str(
+            r#"{"data": {"_parent": {"id": "d875e654", "name": "ops", "enabled": true},
+                "onCallRecipients": ["alice@example.com"]}}"#,
+        )
+        .unwrap();
+        assert_eq!(flat.data.parent.unwrap().id, "d875e654");
+
+        let detailed: DetailedOnCallResult = serde_json::from_str(
+            r#"{"data": {"_parent": {"id": "d875e654", "name": "ops", "enabled": true},
+                "onCallParticipants": []}}"#,
+        )
+        .unwrap();
+        assert_eq!(detailed.data.parent.unwrap().id, "d875e654");
+    }
+
+    #[rstest]
+    #[case::lookup_failed(ContactStatus::LookupFailed)]
+    #[case::contacts_not_expanded(ContactStatus::ContactsNotExpanded)]
+    fn test_first_reachable_person_skips_people_without_number(#[case] status: ContactStatus) {
+        let expected_status = format!("[alice] {}", status.as_str());
+        let unreachable = UserPhoneNumber {
+            phone: vec![],
+            status,
+            ..user("alice", None)
+        };
+        assert_eq!(
+            first_reachable_person(&[unreachable.clone(), user("bob", None)]).unwrap(),
+            ("bob".to_string(), "+493".to_string())
+        );
+
+        let error = first_reachable_person(&[unreachable]).unwrap_err();
+        assert!(matches!(error, Error::NobodyReachable { .. }));
+        assert!(error.to_string().contains(&expected_status));
+    }
+
+    fn numbers(numbers: &[(&str, &[ContactMethod])]) -> PhoneCapabilities {
+        numbers
+            .iter()
+            .map(|(number, methods)| (number.to_string(), methods.iter().copied().collect()))
+            .collect()
+    }
+
+    #[rstest]
+    #[case::expanded(
+        r#"{"data": {"id": "1", "username": "alice", "fullName": "Alice", "userContacts": [
+            {"to": "49-111", "id": "c1", "contactMethod": "voice", "enabled": true},
+            {"to": "alice@example.com", "id": "c2", "contactMethod": "email", "enabled": true}
+        ]}}"#,
+        Some(numbers(&[("+49111", &[ContactMethod::Voice])]))
+    )]
+    #[case::voice_and_sms(
+        r#"{"data": {"id": "1", "username": "alice", "fullName": "Alice", "userContacts": [
+            {"to": "49-111", "id": "c1", "contactMethod": "voice", "enabled": true},
+            {"to": "49111", "id": "c2", "contactMethod": "sms", "enabled": true},
+            {"to": "49-222", "id": "c3", "contactMethod": "sms", "enabled": true}
+        ]}}"#,
+        Some(numbers(&[
+            ("+49111", &[ContactMethod::Voice, ContactMethod::Sms]),
+            ("+49222", &[ContactMethod::Sms]),
+        ]))
+    )]
+    #[case::no_contacts(
+        r#"{"data": {"id": "1", "username": "alice", "fullName": "Alice", "userContacts": []}}"#,
+        Some(PhoneCapabilities::new())
+    )]
+    #[case::not_expanded(
+        r#"{"data": {"id": "1", "username": "alice", "fullName": "Alice"}}"#,
+        None
+    )]
+    fn test_extract_phone_numbers(
+        #[case] response: &str,
+        #[case] expected: Option<PhoneCapabilities>,
+    ) {
+        let contact_information: ContactInformationResult = serde_json::from_str(response).unwrap();
+        assert_eq!(extract_phone_numbers(&contact_information.data), expected);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_schedule_is_not_found() {
+        let opsgenie_config = opsgenie_config(serve_on_calls().await);
+
+        let (users, schedule_id) = get_schedule_users(
+            "ops",
+            "name",
+            &Client::new(),
+            &opsgenie_config,
+            &HeaderMap::new(),
+            &Semaphore::new(1),
+        )
+        .await
+        .unwrap();
+        assert!(users.is_empty());
+        assert_eq!(schedule_id.as_deref(), Some("d875e654"));
+
+        let error = get_schedule_users(
+            "missing",
+            "name",
+            &Client::new(),
+            &opsgenie_config,
+            &HeaderMap::new(),
+            &Semaphore::new(1),
+        )
+        .await
+        .unwrap_err();
+        assert!(
+            matches!(&error, Error::ScheduleNotFound { schedule } if schedule == "missing"),
+            "{error}"
+        );
+        assert!(error.is_schedule_not_found());
+        assert_eq!(error.status_code(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_metadata_requests_fetch_once() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let app = Router::new().route(
+            "/schedules/:schedule",
+            get({
+                let fetches = fetches.clone();
+                move || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    // Keep the fetch in flight long enough for all requests to pile up
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Json(serde_json::json!({"data": {
+                        "id": "d875e654", "name": "ops", "timezone": "Europe/Berlin",
+                        "enabled": true, "rotations": [{"name": "weekly"}]
+                    }}))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let http = Client::new();
+        let opsgenie_config = opsgenie_config(base_url);
+        let cache = ScheduleMetadataCache::default();
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        });
+
+        let requests =
+            (0..10).map(|_| get_schedule_metadata(&schedule, &http, &opsgenie_config, &cache));
+        let results = futures::future::join_all(requests).await;
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        for result in results {
+            assert_eq!(result.unwrap().unwrap().id, "d875e654");
+        }
+    }
+
+    #[test]
+    fn test_count_contact_methods() {
+        let contact_information: ContactInformationResult = serde_json::from_str(
+            r#"{"data": {"id": "1", "username": "alice", "fullName": "Alice", "userContacts": [
+                {"to": "49-111", "id": "c1", "contactMethod": "voice", "enabled": true},
+                {"to": "49111", "id": "c2", "contactMethod": "sms", "enabled": true},
+                {"to": "49-222", "id": "c3", "contactMethod": "sms", "enabled": true},
+                {"to": "alice@example.com", "id": "c4", "contactMethod": "email", "enabled": true}
+            ]}}"#,
+        )
+        .unwrap();
+        let counts = count_contact_methods(&contact_information.data).unwrap();
+        assert_eq!(
+            counts,
+            ContactMethodCounts {
+                voice: 1,
+                sms: 2,
+                filtered: 1,
+            }
+        );
+        assert_eq!(
+            [0, 1, 2, 3, 7].map(contact_count_bucket),
+            ["0", "1", "2", "3+", "3+"]
+        );
+    }
+
+    #[test]
+    fn test_contact_method_metrics() {
+        // The counters are shared by all tests, so this only looks at its own schedule
+        let counts = ContactMethodCounts {
+            voice: 1,
+            sms: 4,
+            filtered: 0,
+        };
+        counts.record("metrics \"test\"");
+        counts.record("metrics \"test\"");
+
+        let metrics = contact_method_metrics();
+        let schedule_lines = metrics
+            .lines()
+            .filter(|line| line.contains(r#"schedule="metrics \"test\"""#))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            schedule_lines,
+            [
+                r#"wygc_contact_methods_total{schedule="metrics \"test\"",method="filtered",contacts="0"} 2"#,
+                r#"wygc_contact_methods_total{schedule="metrics \"test\"",method="sms",contacts="3+"} 2"#,
+                r#"wygc_contact_methods_total{schedule="metrics \"test\"",method="voice",contacts="1"} 2"#,
+            ]
+        );
+        assert!(metrics.starts_with("# HELP wygc_contact_methods_total "));
+    }
+
+    #[tokio::test]
+    async fn test_get_phone_number_retries_with_identifier_type() {
+        // Only finds users when told that the identifier is a username
+        let app = Router::new().route(
+            "/users/:username",
+            get(|Query(query): Query<HashMap<String, String>>| async move {
+                if query.get("identifierType").map(String::as_str) == Some("username") {
+                    Json(serde_json::json!({"data": {
+                        "id": "1", "username": "alice", "fullName": "Alice", "userContacts": [
+                            {"to": "49-111", "id": "c1", "contactMethod": "voice", "enabled": true}
+                        ]
+                    }}))
+                    .into_response()
+                } else {
+                    StatusCode::NOT_FOUND.into_response()
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let capabilities = get_phone_number(
+            Client::new(),
+            base_url.clone(),
+            &HeaderMap::new(),
+            "alice",
+            UserIdentifierType::Username,
+            "identifier-retry",
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            capabilities,
+            Some(numbers(&[("+49111", &[ContactMethod::Voice])]))
+        );
+
+        let error = get_phone_number(
+            Client::new(),
+            base_url,
+            &HeaderMap::new(),
+            "alice",
+            UserIdentifierType::Id,
+            "identifier-retry",
+        )
+        .await
+        .unwrap_err();
+        assert!(error.is_not_found());
+    }
+
+    #[tokio::test]
+    async fn test_schedule_names_are_encoded() {
+        // Remembers the schedule names as the server decoded them
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new().route(
+            "/schedules/:schedule/on-calls",
+            get({
+                let requested = requested.clone();
+                move |Path(schedule): Path<String>| async move {
+                    requested.lock().unwrap().push(schedule);
+                    Json(serde_json::json!({"data": {"onCallRecipients": []}}))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let opsgenie_config = opsgenie_config(base_url);
+        let names = [
+            "ops: primary",
+            "\"night\" shift",
+            "platform/database",
+            "on call?#%&",
+        ];
+        for name in names {
+            get_schedule_users(
+                name,
+                "name",
+                &Client::new(),
+                &opsgenie_config,
+                &HeaderMap::new(),
+                &Semaphore::new(1),
+            )
+            .await
+            .unwrap();
+        }
+
+        assert_eq!(*requested.lock().unwrap(), names);
+    }
+
+    #[tokio::test]
+    async fn test_team_lookup_is_encoded_with_extra_query_params() {
+        // Remembers the team name and query as the server decoded them
+        let requested = Arc::new(Mutex::new(Vec::new()));
+        let app = Router::new().route(
+            "/teams/:team",
+            get({
+                let requested = requested.clone();
+                move |team: Path<String>, query: Query<HashMap<String, String>>| async move {
+                    requested.lock().unwrap().push((team.0, query.0));
+                    Json(serde_json::json!({"data": {"members": [
+                        {"user": {"username": "alice"}}
+                    ]}}))
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let mut opsgenie_config = opsgenie_config(base_url);
+        opsgenie_config.extra_query_params = vec![("tenant".to_string(), "acme".to_string())];
+        let members = get_team_members(
+            &Client::new(),
+            &opsgenie_config,
+            &HeaderMap::new(),
+            "platform/db: \"night\"",
+            &Semaphore::new(1),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(members, ["alice"]);
+        let requested = requested.lock().unwrap();
+        let (team, query) = &requested[0];
+        assert_eq!(team, "platform/db: \"night\"");
+        assert_eq!(query.get("tenant").map(String::as_str), Some("acme"));
+        assert_eq!(
+            query.get("identifierType").map(String::as_str),
+            Some("name")
+        );
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_lookups_stay_within_upstream_permits() {
+        // Tracks how many contact lookups are being answered at the same time
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/schedules/:schedule/on-calls",
+                get(|| async {
+                    Json(serde_json::json!({"data": {
+                        "onCallRecipients": ["alice", "bob", "carol"]
+                    }}))
+                }),
+            )
+            .route(
+                "/users/:username",
+                get({
+                    let in_flight = in_flight.clone();
+                    let max_in_flight = max_in_flight.clone();
+                    move |Path(username): Path<String>| async move {
+                        let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        max_in_flight.fetch_max(current, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(20)).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        Json(serde_json::json!({"data": {
+                            "id": "1", "username": username, "fullName": "", "userContacts": []
+                        }}))
+                    }
+                }),
+            );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let base_url = Url::parse(&format!("http://{}/", listener.local_addr().unwrap())).unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let opsgenie_config = opsgenie_config(base_url);
+        let http = Client::new();
+        let headers = HeaderMap::new();
+        let upstream_permits = Semaphore::new(2);
+        let requests = (0..10).map(|_| {
+            get_schedule_users(
+                "ops",
+                "name",
+                &http,
+                &opsgenie_config,
+                &headers,
+                &upstream_permits,
+            )
+        });
+        for result in futures::future::join_all(requests).await {
+            assert_eq!(result.unwrap().0.len(), 3);
+        }
+
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,360 @@
+use crate::config::AlertChannel;
+use crate::queue::error::{
+    CreateDirectorySnafu, ListEntriesSnafu, ParseEntrySnafu, ReadEntrySnafu, RemoveEntrySnafu,
+    SerializeEntrySnafu, WriteEntrySnafu,
+};
+use crate::twilio::AlertResult;
+use crate::Schedule;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Notify;
+
+static ENTRY_EXTENSION: &str = "json";
+// Outcomes of processed alerts are stored next to the queued alerts, with their own extension so
+// they are never picked up as pending
+static STATUS_EXTENSION: &str = "status";
+
+// Number of processed alerts whose outcome can still be looked up
+static FINISHED_ALERTS_KEPT: usize = 1000;
+
+// Makes ids unique when several alerts are accepted within the same nanosecond
+static ENTRY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Snafu, Debug)]
+#[snafu(module)]
+pub(crate) enum Error {
+    #[snafu(display("failed to create alert queue directory [{}]: \n{source}", path.display()))]
+    CreateDirectory {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to serialize queued alert: \n{source}"))]
+    SerializeEntry { source: serde_json::Error },
+    #[snafu(display("failed to serialize status of alert [{id}]: \n{source}"))]
+    SerializeStatus {
+        source: serde_json::Error,
+        id: String,
+    },
+    #[snafu(display("failed to write queued alert [{}]: \n{source}", path.display()))]
+    WriteEntry {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to list queued alerts in [{}]: \n{source}", path.display()))]
+    ListEntries {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to read queued alert [{}]: \n{source}", path.display()))]
+    ReadEntry {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to parse queued alert [{}]: \n{source}", path.display()))]
+    ParseEntry {
+        source: serde_json::Error,
+        path: PathBuf,
+    },
+    #[snafu(display("failed to remove queued alert [{}]: \n{source}", path.display()))]
+    RemoveEntry {
+        source: std::io::Error,
+        path: PathBuf,
+    },
+}
+
+/// An alert that has been accepted, but not processed yet
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct QueuedAlert {
+    pub id: String,
+    pub accepted_at: DateTime<Utc>,
+    pub schedule: Schedule,
+    pub channel: AlertChannel,
+    pub idempotency_key: Option<String>,
+    // Numbers that were already dialed for this alert, each one is recorded right before it is
+    // dialed, see `DialProgress`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dialed_numbers: Vec<String>,
+    // Numbers that were called because Twilio rejected their message, recorded the same way
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallback_numbers: Vec<String>,
+}
+
+/// Where an accepted alert is at
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Clone)]
+#[serde(rename_all = "camelCase", tag = "status")]
+pub enum AlertStatus {
+    Queued,
+    Completed { result: AlertResult },
+    Failed { error: String },
+}
+
+/// Durable queue of accepted alerts, every alert is stored as one file in the queue directory.
+/// Files are written to a temporary name, synced and then renamed, so the worker never sees a
+/// partially written alert. File names start with the time the alert was accepted, which makes
+/// the directory listing the processing order.
+/// Outcomes of processed alerts are stored the same way, so they can still be looked up after a
+/// restart.
+#[derive(Debug, Clone)]
+pub struct AlertQueue {
+    directory: PathBuf,
+    // Wakes up the worker right away when an alert is enqueued
+    enqueued: Arc<Notify>,
+}
+
+impl AlertQueue {
+    pub async fn open(directory: PathBuf) -> Result<Self, Error> {
+        tokio::fs::create_dir_all(&directory)
+            .await
+            .context(CreateDirectorySnafu { path: &directory })?;
+        Ok(AlertQueue {
+            directory,
+            enqueued: Arc::new(Notify::new()),
+        })
+    }
+
+    pub async fn enqueue(
+        &self,
+        schedule: Schedule,
+        channel: AlertChannel,
+        idempotency_key: Option<String>,
+    ) -> Result<QueuedAlert, Error> {
+        let accepted_at = Utc::now();
+        let id = format!(
+            "{}-{}-{}",
+            accepted_at.format("%Y%m%dT%H%M%S%.9fZ"),
+            std::process::id(),
+            ENTRY_COUNTER.fetch_add(1, Ordering::Relaxed)
+        );
+        let alert = QueuedAlert {
+            id,
+            accepted_at,
+            schedule,
+            channel,
+            idempotency_key,
+            dialed_numbers: vec![],
+            fallback_numbers: vec![],
+        };
+        self.write(&alert).await?;
+
+        self.enqueued.notify_one();
+        Ok(alert)
+    }
+
+    /// Writes the alert to its file in the queue directory, replacing an earlier version of it
+    async fn write(&self, alert: &QueuedAlert) -> Result<(), Error> {
+        let content = serde_json::to_vec(alert).context(SerializeEntrySnafu)?;
+        self.write_file(&alert.id, ENTRY_EXTENSION, &content).await
+    }
+
+    async fn write_file(&self, id: &str, extension: &str, content: &[u8]) -> Result<(), Error> {
+        let path = self.directory.join(format!("{id}.{extension}"));
+        let temporary_path = self.directory.join(format!("{id}.{extension}.tmp"));
+        write_synced(&temporary_path, content)
+            .await
+            .context(WriteEntrySnafu {
+                path: &temporary_path,
+            })?;
+        tokio::fs::rename(&temporary_path, &path)
+            .await
+            .context(WriteEntrySnafu { path: &path })?;
+        // Make sure the rename itself survives a crash as well
+        let directory = tokio::fs::File::open(&self.directory)
+            .await
+            .context(WriteEntrySnafu {
+                path: &self.directory,
+            })?;
+        directory.sync_all().await.context(WriteEntrySnafu {
+            path: &self.directory,
+        })
+    }
+
+    /// Paths of all alerts that still have to be processed, oldest first
+    pub async fn pending(&self) -> Result<Vec<PathBuf>, Error> {
+        self.list(ENTRY_EXTENSION).await
+    }
+
+    /// Paths of all files with the extension in the queue directory, oldest first
+    async fn list(&self, extension: &str) -> Result<Vec<PathBuf>, Error> {
+        let mut entries = tokio::fs::read_dir(&self.directory)
+            .await
+            .context(ListEntriesSnafu {
+                path: &self.directory,
+            })?;
+        let mut paths = Vec::new();
+        while let Some(entry) = entries.next_entry().await.context(ListEntriesSnafu {
+            path: &self.directory,
+        })? {
+            let path = entry.path();
+            if path
+                .extension()
+                .is_some_and(|path_extension| path_extension == extension)
+            {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+        Ok(paths)
+    }
+
+    pub async fn read(&self, path: &Path) -> Result<QueuedAlert, Error> {
+        let content = tokio::fs::read(path)
+            .await
+            .context(ReadEntrySnafu { path })?;
+        serde_json::from_slice(&content).context(ParseEntrySnafu { path })
+    }
+
+    /// Removes a processed alert from the queue
+    pub async fn complete(&self, path: &Path) -> Result<(), Error> {
+        tokio::fs::remove_file(path)
+            .await
+            .context(RemoveEntrySnafu { path })
+    }
+
+    /// Stores the outcome of a processed alert, should be called before it is completed so that
+    /// the alert can be looked up at any time. Only the outcomes of the most recent alerts are kept.
+    pub async fn finish(&self, id: &str, status: AlertStatus) -> Result<(), Error> {
+        let content = serde_json::to_vec(&status).context(SerializeStatusSnafu { id })?;
+        self.write_file(id, STATUS_EXTENSION, &content).await?;
+
+        // File names start with the time the alert was accepted, so the oldest ones come first
+        let statuses = self.list(STATUS_EXTENSION).await?;
+        let expired = statuses.len().saturating_sub(FINISHED_ALERTS_KEPT);
+        for path in &statuses[..expired] {
+            tokio::fs::remove_file(path)
+                .await
+                .context(RemoveEntrySnafu { path })?;
+        }
+        Ok(())
+    }
+
+    /// Status of an accepted alert, `None` if it is unknown or its outcome has already been
+    /// forgotten
+    pub async fn status(&self, id: &str) -> Option<AlertStatus> {
+        // Ids are only ever used as file names we created ourselves
+        let valid_id = !id.starts_with('.')
+            && id
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+        if !valid_id {
+            return None;
+        }
+
+        let status_path = self.directory.join(format!("{id}.{STATUS_EXTENSION}"));
+        match tokio::fs::read(&status_path).await {
+            Ok(content) => match serde_json::from_slice(&content) {
+                Ok(status) => return Some(status),
+                Err(error) => tracing::warn!(
+                    error = &error as &dyn std::error::Error,
+                    path = %status_path.display(),
+                    "Failed to parse stored alert status"
+                ),
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+            Err(error) => tracing::warn!(
+                error = &error as &dyn std::error::Error,
+                path = %status_path.display(),
+                "Failed to read stored alert status"
+            ),
+        }
+        let path = self.directory.join(format!("{id}.{ENTRY_EXTENSION}"));
+        tokio::fs::try_exists(&path)
+            .await
+            .unwrap_or(false)
+            .then_some(AlertStatus::Queued)
+    }
+
+    /// Waits until an alert is enqueued, but at most `poll_interval`
+    pub async fn wait(&self, poll_interval: Duration) {
+        let _ = tokio::time::timeout(poll_interval, self.enqueued.notified()).await;
+    }
+}
+
+/// Keeps track of the calls of a queued alert while it is processed, every number is written to
+/// the alert's entry before it is dialed. If the service stops while the alert is processed, the
+/// alert is processed again after a restart and only the numbers that were not dialed yet are
+/// called.
+#[derive(Debug)]
+pub struct DialProgress {
+    queue: AlertQueue,
+    // Calls of one alert are placed concurrently, every write has to include all earlier ones
+    alert: tokio::sync::Mutex<QueuedAlert>,
+}
+
+impl DialProgress {
+    pub fn new(queue: AlertQueue, alert: QueuedAlert) -> Self {
+        DialProgress {
+            queue,
+            alert: tokio::sync::Mutex::new(alert),
+        }
+    }
+
+    pub async fn dialed_numbers(&self) -> Vec<String> {
+        self.alert.lock().await.dialed_numbers.clone()
+    }
+
+    pub async fn fallback_numbers(&self) -> Vec<String> {
+        self.alert.lock().await.fallback_numbers.clone()
+    }
+
+    /// Has to be called before the number is dialed on `channel`, a call that was recorded is never
+    /// placed again for this alert. Calls on another channel than the alert's are fallbacks.
+    pub async fn record(&self, number: &str, channel: AlertChannel) -> Result<(), Error> {
+        let mut alert = self.alert.lock().await;
+        if channel == alert.channel {
+            alert.dialed_numbers.push(number.to_string());
+        } else {
+            alert.fallback_numbers.push(number.to_string());
+        }
+        self.queue.write(&alert).await
+    }
+}
+
+async fn write_synced(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(content).await?;
+    file.sync_all().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AlertQueue, AlertStatus};
+    use crate::config::AlertChannel;
+    use crate::{Schedule, ScheduleRequestByName};
+
+    #[tokio::test]
+    async fn test_status_of_processed_alert_survives_restart() {
+        let directory = std::env::temp_dir().join(format!("alert-queue-{}", std::process::id()));
+        let queue = AlertQueue::open(directory.clone()).await.unwrap();
+        let schedule = Schedule::ScheduleByName(ScheduleRequestByName {
+            name: "ops".to_string(),
+        });
+        let queued = queue
+            .enqueue(schedule, AlertChannel::Voice, None)
+            .await
+            .unwrap();
+        assert_eq!(queue.status(&queued.id).await, Some(AlertStatus::Queued));
+
+        let [path] = queue.pending().await.unwrap().try_into().unwrap();
+        let failed = AlertStatus::Failed {
+            error: "nobody is on call".to_string(),
+        };
+        queue.finish(&queued.id, failed.clone()).await.unwrap();
+        queue.complete(&path).await.unwrap();
+        // Stored outcomes are never picked up as pending alerts
+        assert!(queue.pending().await.unwrap().is_empty());
+
+        let reopened = AlertQueue::open(directory.clone()).await.unwrap();
+        assert_eq!(reopened.status(&queued.id).await, Some(failed));
+        assert_eq!(reopened.status("unknown").await, None);
+        assert_eq!(reopened.status("../queued").await, None);
+
+        tokio::fs::remove_dir_all(directory).await.unwrap();
+    }
+}
@@ -1,7 +1,13 @@
+use crate::config::{AlertChannel, InvalidNumberPolicy, ScheduleParameter};
 use crate::config::{Config, TwilioConfig};
-use crate::twilio::error::BuildUrlSnafu;
-use crate::util::send_json_request;
-use crate::{http_error, AlertInfo};
+use crate::opsgenie::{ContactMethod, UserPhoneNumber};
+use crate::queue::DialProgress;
+use crate::twilio::error::{
+    BuildUrlSnafu, InvalidNumbersSnafu, LookUpOutgoingNumberSnafu, MissingAccountSidSnafu,
+    OutgoingNumberNotOwnedSnafu, PagingBudgetExceededSnafu,
+};
+use crate::util::{is_e164, send_json_request, RateLimiter, TtlCache};
+use crate::{http_error, AlertInfo, Schedule};
 use axum::http::header::AUTHORIZATION;
 use axum::http::{HeaderMap, StatusCode};
 use futures::future::join_all;
@@ -9,7 +15,11 @@ use reqwest::Client;
 use secrecy::ExposeSecret;
 use serde::{Deserialize, Serialize};
 use snafu::{ResultExt, Snafu};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, VecDeque};
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::Semaphore;
 use tracing::instrument;
 use url::{ParseError, Url};
 use urlencoding::encode;
@@ -22,6 +32,26 @@ pub(crate) enum Error {
     RunWorkflow { source: crate::util::Error },
     #[snafu(display("Error creating url for Twilio workflow"))]
     BuildUrl { source: url::ParseError },
+    #[snafu(display("Refusing to alert, these numbers are not valid E.164 numbers: {numbers:?}"))]
+    InvalidNumbers { numbers: Vec<String> },
+    #[snafu(display(
+        "Global paging budget exceeded: alert needs {requested} calls, but only {remaining} of {budget} are left for the last {} seconds", window.as_secs()
+    ))]
+    PagingBudgetExceeded {
+        requested: usize,
+        remaining: usize,
+        budget: usize,
+        window: Duration,
+    },
+    #[snafu(display("No Twilio account sid configured, can't look up the outgoing number"))]
+    MissingAccountSid,
+    #[snafu(display("Looking up the outgoing number [{number}] failed"))]
+    LookUpOutgoingNumber {
+        source: crate::util::Error,
+        number: String,
+    },
+    #[snafu(display("The outgoing number [{number}] is not owned by the Twilio account"))]
+    OutgoingNumberNotOwned { number: String },
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Eq, Hash)]
@@ -30,21 +60,137 @@ pub struct TwilioResponse {
     pub status: String,
 }
 
+// Body Twilio sends along with error responses
+#[derive(Clone, Deserialize, Debug, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+struct TwilioErrorResponse {
+    code: Option<u32>,
+    message: Option<String>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct IncomingPhoneNumbersResult {
+    incoming_phone_numbers: Vec<IncomingPhoneNumber>,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct IncomingPhoneNumber {
+    phone_number: String,
+}
+
+// Error codes Twilio uses when a trial account tries to reach a number that isn't verified
+static TRIAL_ACCOUNT_ERROR_CODES: [u32; 2] = [21219, 21608];
+
 impl http_error::Error for crate::twilio::Error {
     fn status_code(&self) -> StatusCode {
         match self {
             Error::RunWorkflow { .. } => StatusCode::INTERNAL_SERVER_ERROR,
             Error::BuildUrl { .. } => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::InvalidNumbers { .. } => StatusCode::UNPROCESSABLE_ENTITY,
+            Error::PagingBudgetExceeded { .. } => StatusCode::TOO_MANY_REQUESTS,
+            Error::MissingAccountSid => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::LookUpOutgoingNumber { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            Error::OutgoingNumberNotOwned { .. } => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 }
 
 #[instrument(name = "dial_outgoing")]
 pub async fn alert(
-    numbers: &Vec<String>,
+    plan: &AlertPlan,
+    progress: Option<&DialProgress>,
+    paging_budget: &PagingBudget,
     http: &Client,
     config: &Config,
+    upstream_permits: &Semaphore,
 ) -> Result<AlertResult, crate::twilio::Error> {
+    let twilio_config = &config.twilio_config;
+    let results = place_calls(
+        &plan.calls,
+        plan.channel,
+        &plan.schedule_parameters,
+        progress,
+        http,
+        config,
+        upstream_permits,
+    )
+    .await?;
+
+    let mut response = AlertResult {
+        overall_result: OverallResult::Success,
+        detailed_result: vec![],
+        skipped_numbers: plan.skipped_numbers.clone(),
+        idempotency_key: None,
+        suppressed: None,
+        fallbacks: vec![],
+    };
+    let mut fallback_calls = Vec::new();
+    for (call, result) in results {
+        if let Err(e) = &result {
+            if plan.channel == AlertChannel::Sms
+                && twilio_config.sms_fallback_to_voice
+                && falls_back_to_voice(e)
+            {
+                fallback_calls.push(call.clone());
+            }
+        }
+        response
+            .detailed_result
+            .push(dial_number_result(call, result, twilio_config));
+    }
+
+    if !fallback_calls.is_empty() {
+        // Fallback calls are paid for like any other call
+        match paging_budget.try_spend(fallback_calls.len()) {
+            Ok(()) => {
+                tracing::warn!(
+                    ?fallback_calls,
+                    "Twilio rejected the messages to these numbers, calling them instead"
+                );
+                let results = place_calls(
+                    &fallback_calls,
+                    AlertChannel::Voice,
+                    &plan.schedule_parameters,
+                    progress,
+                    http,
+                    config,
+                    upstream_permits,
+                )
+                .await?;
+                response.fallbacks = results
+                    .into_iter()
+                    .map(|(call, result)| dial_number_result(call, result, twilio_config))
+                    .collect();
+            }
+            Err(e) => tracing::error!(
+                error = &e as &dyn std::error::Error,
+                ?fallback_calls,
+                "Not calling the numbers whose messages were rejected"
+            ),
+        }
+    }
+    response.update_overall_result();
+
+    // TODO: Properly parse the responses and generate a response here that distinguishes between
+    //  1. HTTP Error Code
+    //  2. HTTP 200 and "active=true"
+    //  3. HTTP 200 but "active != true" - we suspect that this would mean a Twilio-side failure
+    Ok(response)
+}
+
+// A placed call together with Twilio's response to it
+type CallOutcome = (PlannedCall, Result<TwilioResponse, crate::util::Error>);
+
+/// Starts one execution of the Twilio flow per call on the given channel, all at the same time
+async fn place_calls(
+    calls: &[PlannedCall],
+    channel: AlertChannel,
+    schedule_parameters: &BTreeMap<String, String>,
+    progress: Option<&DialProgress>,
+    http: &Client,
+    config: &Config,
+    upstream_permits: &Semaphore,
+) -> Result<Vec<CallOutcome>, crate::twilio::Error> {
     let twilio_config = &config.twilio_config;
     tracing::trace!(?twilio_config.base_url, "url_builder before adding workflow"
     );
@@ -64,22 +210,51 @@ pub async fn alert(
     // .. no we won't, we are parallelizing here, so we clone
     let mut params = HashMap::new();
     params.insert("From", twilio_config.outgoing_number.clone());
+    // Everything in `Parameters` is available to the Studio flow as `flow.data`
+    let mut flow_parameters = serde_json::Map::new();
+    flow_parameters.insert("channel".to_string(), channel.as_str().into());
+    for (name, value) in schedule_parameters {
+        flow_parameters.insert(name.clone(), value.clone().into());
+    }
+    if let Some(caller_id) = &twilio_config.caller_id {
+        flow_parameters.insert("callerId".to_string(), caller_id.clone().into());
+    }
+    params.insert(
+        "Parameters",
+        serde_json::Value::Object(flow_parameters).to_string(),
+    );
     tracing::info!(
-        ?numbers,
+        ?calls,
         ?url_builder,
         ?params,
         twilio_config.outgoing_number,
         "These numbers will be alerted via Twilio."
     );
 
-    let requests = numbers
+    let requests = calls
         .iter()
-        .map(|number| async {
+        .map(|call| async {
             let mut my_params = params.clone();
-            my_params.insert("To", number.clone());
+            my_params.insert("To", call.number.clone());
 
+            // Shared with the OpsGenie lookups, this bounds the total work we have in flight
+            // upstream
+            let _permit = upstream_permits
+                .acquire()
+                .await
+                .expect("upstream request semaphore is never closed");
+            if let Some(progress) = progress {
+                // Paging is more important than not calling somebody twice after a restart
+                if let Err(e) = progress.record(&call.number, channel).await {
+                    tracing::warn!(
+                        error = &e as &dyn std::error::Error,
+                        call.number,
+                        "Failed to record call in the alert queue, it will be placed again if the alert is processed again"
+                    );
+                }
+            }
             (
-                number.clone(),
+                call.clone(),
                 send_json_request::<TwilioResponse>(
                     http.post(url_builder.clone())
                         .headers(outgoing_headers.clone())
@@ -90,51 +265,452 @@ pub async fn alert(
         })
         .collect::<Vec<_>>();
 
-    let results = join_all(requests).await;
+    Ok(join_all(requests).await)
+}
 
-    //results.iter().map(|(number, result)| result.and_then())
+/// Twilio rejected the message because of the number, a call might still reach it, e.g. for
+/// landlines that can't receive messages.
+/// Only rejections Twilio reports when the flow execution is created are seen here, the message is
+/// sent by the Studio flow and its later delivery status is not available to us.
+fn falls_back_to_voice(error: &crate::util::Error) -> bool {
+    matches!(
+        classify_failure(error).0,
+        DialErrorCode::CarrierRejected | DialErrorCode::InvalidNumber
+    )
+}
 
-    let mut response = AlertResult {
-        overall_result: OverallResult::Success,
-        detailed_result: vec![],
-    };
-    for (number, result) in results {
-        response.detailed_result.push(match result {
-            Ok(response) => {
-                if response.status.eq("active") {
-                    DialNumberResult::Success { number }
-                } else {
-                    DialNumberResult::Unknown {
-                        number,
-                        status: response.status,
-                    }
+fn dial_number_result(
+    PlannedCall { number, usernames }: PlannedCall,
+    result: Result<TwilioResponse, crate::util::Error>,
+    twilio_config: &TwilioConfig,
+) -> DialNumberResult {
+    match result {
+        Ok(response) => {
+            if response.status.eq("active") {
+                DialNumberResult::Success { number, usernames }
+            } else {
+                DialNumberResult::Unknown {
+                    number,
+                    usernames,
+                    status: response.status,
                 }
             }
-            Err(e) => DialNumberResult::Failure {
+        }
+        Err(e) => {
+            let (error_code, twilio_error_code) = if twilio_config.include_error_codes {
+                let (error_code, twilio_error_code) = classify_failure(&e);
+                (Some(error_code), twilio_error_code)
+            } else {
+                (None, None)
+            };
+            DialNumberResult::Failure {
+                error: describe_failure(&e, &number),
+                error_code,
+                twilio_error_code,
                 number,
-                error: e.to_string(),
-            },
-        });
+                usernames,
+            }
+        }
     }
-    response.update_overall_result();
-
-    // TODO: Properly parse the responses and generate a response here that distinguishes between
-    //  1. HTTP Error Code
-    //  2. HTTP 200 and "active=true"
-    //  3. HTTP 200 but "active != true" - we suspect that this would mean a Twilio-side failure
-    Ok(response)
 }
 
 pub fn get_base_url() -> Result<Url, url::ParseError> {
     Url::parse(crate::twilio::TWILIO_BASEURL)
 }
 
+fn parse_twilio_error(error: &crate::util::Error) -> Option<TwilioErrorResponse> {
+    match error {
+        crate::util::Error::HttpErrorResponse { text, .. } => serde_json::from_str(text).ok(),
+        _ => None,
+    }
+}
+
+/// Stable classification of why a call could not be placed, so consumers can handle failures
+/// without parsing the error message
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum DialErrorCode {
+    TrialAccountRestriction,
+    InvalidNumber,
+    CarrierRejected,
+    RateLimited,
+    Unauthorized,
+    // Twilio rejected the call for a reason not covered above, see `twilioErrorCode`
+    TwilioError,
+    // Twilio could not be reached or sent a response we don't understand
+    RequestFailed,
+}
+
+// Twilio error codes for numbers that can't be called at all
+static INVALID_NUMBER_ERROR_CODES: [u32; 4] = [21211, 21214, 21217, 21614];
+// Twilio error codes for numbers that are valid, but the call was refused on the way there
+static CARRIER_REJECTED_ERROR_CODES: [u32; 2] = [21610, 21612];
+
+/// Maps a failed request to our error code and the error code reported by Twilio, if any
+fn classify_failure(error: &crate::util::Error) -> (DialErrorCode, Option<u32>) {
+    let Some(twilio_error_code) =
+        parse_twilio_error(error).and_then(|twilio_error| twilio_error.code)
+    else {
+        return (DialErrorCode::RequestFailed, None);
+    };
+    let error_code = match twilio_error_code {
+        code if TRIAL_ACCOUNT_ERROR_CODES.contains(&code) => DialErrorCode::TrialAccountRestriction,
+        code if INVALID_NUMBER_ERROR_CODES.contains(&code) => DialErrorCode::InvalidNumber,
+        code if CARRIER_REJECTED_ERROR_CODES.contains(&code) => DialErrorCode::CarrierRejected,
+        20429 => DialErrorCode::RateLimited,
+        20003 => DialErrorCode::Unauthorized,
+        _ => DialErrorCode::TwilioError,
+    };
+    (error_code, Some(twilio_error_code))
+}
+
+/// Turns a failed request into the error message that is returned for the number.
+/// Trial accounts failing because the number isn't verified get an explicit message, as the
+/// generic error is very confusing while evaluating this with a Twilio trial account.
+fn describe_failure(error: &crate::util::Error, number: &str) -> String {
+    let is_trial_restriction = parse_twilio_error(error)
+        .and_then(|twilio_error| twilio_error.code)
+        .is_some_and(|code| TRIAL_ACCOUNT_ERROR_CODES.contains(&code));
+    if is_trial_restriction {
+        tracing::warn!(
+            number,
+            "Twilio rejected the call because the account is a trial account"
+        );
+        format!("Twilio trial account can only call verified numbers, verify [{number}] in the Twilio console or upgrade the account")
+    } else {
+        error.to_string()
+    }
+}
+
+/// Describes everything that `alert` would do for a set of on call persons, without talking to
+/// Twilio.
+/// The actual alerting is driven by this plan as well, so a simulated plan and a real alert can't
+/// drift apart.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertPlan {
+    pub workflow_id: String,
+    pub outgoing_number: String,
+    pub strategy: DialStrategy,
+    pub channel: AlertChannel,
+    // Configured information about the schedule that is passed on to the Twilio flow
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub schedule_parameters: BTreeMap<String, String>,
+    pub calls: Vec<PlannedCall>,
+    // Users that are on call but will not be alerted, because they have no phone number
+    pub unreachable_users: Vec<String>,
+    // Calls that will not be placed, because the number is not a valid E.164 number
+    pub skipped_numbers: Vec<PlannedCall>,
+    // Calls that will not be placed, because the number is not registered for the channel
+    pub unsupported_numbers: Vec<PlannedCall>,
+}
+
+/// How the planned calls are executed
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub enum DialStrategy {
+    /// All numbers are dialed at the same time
+    Parallel,
+}
+
+/// A single call that will be placed, a number that is shared by several on call persons is only
+/// called once unless configured otherwise, so there can be more than one user per call.
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct PlannedCall {
+    pub number: String,
+    pub usernames: Vec<String>,
+}
+
+/// Part of the startup self-test: makes sure the configured outgoing number is one of the
+/// incoming phone numbers of the Twilio account, otherwise every call would fail once it is needed.
+pub async fn check_outgoing_number(http: &Client, config: &Config) -> Result<(), Error> {
+    let twilio_config = &config.twilio_config;
+    let account_sid = twilio_config
+        .account_sid
+        .as_ref()
+        .context(MissingAccountSidSnafu)?;
+    let number = &twilio_config.outgoing_number;
+
+    let mut url = twilio_config
+        .api_base_url
+        .join(&format!(
+            "Accounts/{}/IncomingPhoneNumbers.json",
+            encode(account_sid)
+        ))
+        .context(BuildUrlSnafu)?;
+    url.query_pairs_mut().append_pair("PhoneNumber", number);
+
+    let result = send_json_request::<IncomingPhoneNumbersResult>(http.get(url).header(
+        AUTHORIZATION,
+        twilio_config.credentials.expose_secret().clone().0,
+    ))
+    .await
+    .context(LookUpOutgoingNumberSnafu { number })?;
+
+    snafu::ensure!(
+        result
+            .incoming_phone_numbers
+            .iter()
+            .any(|owned| &owned.phone_number == number),
+        OutgoingNumberNotOwnedSnafu { number }
+    );
+    tracing::info!(number, "Outgoing number is owned by the Twilio account");
+    Ok(())
+}
+
+/// Caps the number of calls and messages that are sent across all alerts within a rolling window,
+/// so a misbehaving caller can't run up an unbounded Twilio bill.
+#[derive(Debug, Clone)]
+pub struct PagingBudget {
+    // No limit if not set
+    budget: Option<usize>,
+    window: Duration,
+    // When each call within the current window was spent, oldest first
+    spent: Arc<Mutex<VecDeque<Instant>>>,
+    // The exceeded budget is reported once per window, not for every refused alert
+    exceeded_reports: RateLimiter<()>,
+}
+
+impl PagingBudget {
+    pub fn new(config: &TwilioConfig) -> Self {
+        PagingBudget {
+            budget: config.paging_budget,
+            window: config.paging_budget_window,
+            spent: Arc::default(),
+            exceeded_reports: RateLimiter::new(1, config.paging_budget_window),
+        }
+    }
+
+    /// Whether a refused alert should be reported, true at most once per window
+    pub fn report_exceeded(&self) -> bool {
+        self.exceeded_reports.try_acquire(())
+    }
+
+    /// Spends `requested` calls from the budget, or none at all if not enough are left
+    pub fn try_spend(&self, requested: usize) -> Result<(), Error> {
+        self.spend(requested, true)
+    }
+
+    /// Checks whether `requested` calls could be spent right now, without spending them
+    pub fn check(&self, requested: usize) -> Result<(), Error> {
+        self.spend(requested, false)
+    }
+
+    fn spend(&self, requested: usize, commit: bool) -> Result<(), Error> {
+        let Some(budget) = self.budget else {
+            return Ok(());
+        };
+        let window = self.window;
+        let now = Instant::now();
+
+        let mut spent = self
+            .spent
+            .lock()
+            .expect("paging budget lock is never poisoned");
+        while spent
+            .front()
+            .is_some_and(|spent_at| now.duration_since(*spent_at) >= window)
+        {
+            spent.pop_front();
+        }
+        let remaining = budget.saturating_sub(spent.len());
+        snafu::ensure!(
+            requested <= remaining,
+            PagingBudgetExceededSnafu {
+                requested,
+                remaining,
+                budget,
+                window,
+            }
+        );
+        if commit {
+            spent.extend(std::iter::repeat(now).take(requested));
+        }
+        Ok(())
+    }
+}
+
+impl AlertPlan {
+    /// Number of calls and messages this plan sends, alerting on both channels costs twice
+    pub fn paging_cost(&self) -> usize {
+        match self.channel {
+            AlertChannel::Both => self.calls.len() * 2,
+            AlertChannel::Voice | AlertChannel::Sms => self.calls.len(),
+        }
+    }
+}
+
+pub fn plan_alert(
+    schedule: &Schedule,
+    people_to_alert: &AlertInfo,
+    channel: AlertChannel,
+    config: &Config,
+) -> Result<AlertPlan, crate::twilio::Error> {
+    let twilio_config = &config.twilio_config;
+
+    let schedule_parameters = twilio_config
+        .schedule_parameters
+        .iter()
+        .filter_map(|parameter| {
+            let value = match (parameter, schedule) {
+                (ScheduleParameter::ScheduleId, _) => people_to_alert.schedule_id.clone(),
+                (ScheduleParameter::ScheduleName, Schedule::ScheduleByName(by_name)) => {
+                    Some(by_name.name.clone())
+                }
+                (ScheduleParameter::ScheduleGroup, Schedule::ScheduleByGroup(by_group)) => {
+                    Some(by_group.group.clone())
+                }
+                // Not known for this kind of request
+                _ => None,
+            };
+            value.map(|value| (parameter.as_str().to_string(), value))
+        })
+        .collect();
+
+    let calls = plan_calls(
+        &people_to_alert.full_information,
+        twilio_config.treat_shared_numbers_individually,
+    );
+    let (calls, skipped_numbers) =
+        apply_invalid_number_policy(calls, &twilio_config.invalid_number_policy)?;
+    let (calls, unsupported_numbers) =
+        filter_by_channel(calls, &people_to_alert.full_information, channel);
+    let unreachable_users = people_to_alert
+        .full_information
+        .iter()
+        .filter(|person| person.phone.is_empty())
+        .map(|person| person.name.clone())
+        .collect();
+
+    Ok(AlertPlan {
+        workflow_id: twilio_config.workflow_id.clone(),
+        outgoing_number: twilio_config.outgoing_number.clone(),
+        strategy: DialStrategy::Parallel,
+        channel,
+        schedule_parameters,
+        calls,
+        unreachable_users,
+        skipped_numbers,
+        unsupported_numbers,
+    })
+}
+
+/// Splits off the calls to numbers that are registered in OpsGenie, but not for a contact method
+/// the channel needs, e.g. a landline that can't receive messages. Numbers we know nothing about
+/// are kept, a failed contact lookup shouldn't keep anybody from being alerted.
+/// Returns the calls to place and the calls that were skipped.
+fn filter_by_channel(
+    calls: Vec<PlannedCall>,
+    people: &[UserPhoneNumber],
+    channel: AlertChannel,
+) -> (Vec<PlannedCall>, Vec<PlannedCall>) {
+    let (calls, unsupported): (Vec<_>, Vec<_>) = calls.into_iter().partition(|call| {
+        // A shared number can be registered differently by each person
+        let methods: BTreeSet<ContactMethod> = people
+            .iter()
+            .filter_map(|person| person.capabilities.get(&call.number))
+            .flatten()
+            .copied()
+            .collect();
+        methods.is_empty()
+            || match channel {
+                AlertChannel::Voice => methods.contains(&ContactMethod::Voice),
+                AlertChannel::Sms => methods.contains(&ContactMethod::Sms),
+                // Whichever of the two the number supports will reach the person
+                AlertChannel::Both => true,
+            }
+    });
+    if !unsupported.is_empty() {
+        tracing::warn!(
+            ?unsupported,
+            channel = channel.as_str(),
+            "Skipping numbers that are not registered for the channel"
+        );
+    }
+    (calls, unsupported)
+}
+
+/// Splits off the calls to numbers that are not valid E.164 numbers, depending on the policy they
+/// are skipped, fail the whole request or are passed to Twilio unchanged.
+/// Returns the calls to place and the calls that were skipped.
+fn apply_invalid_number_policy(
+    calls: Vec<PlannedCall>,
+    policy: &InvalidNumberPolicy,
+) -> Result<(Vec<PlannedCall>, Vec<PlannedCall>), crate::twilio::Error> {
+    let invalid_numbers: Vec<String> = calls
+        .iter()
+        .filter(|call| !is_e164(&call.number))
+        .map(|call| call.number.clone())
+        .collect();
+    if invalid_numbers.is_empty() {
+        return Ok((calls, vec![]));
+    }
+
+    match policy {
+        InvalidNumberPolicy::Skip => {
+            tracing::warn!(
+                ?invalid_numbers,
+                "Skipping numbers that are not valid E.164 numbers"
+            );
+            Ok(calls.into_iter().partition(|call| is_e164(&call.number)))
+        }
+        InvalidNumberPolicy::FailRequest => InvalidNumbersSnafu {
+            numbers: invalid_numbers,
+        }
+        .fail(),
+        InvalidNumberPolicy::DialAnyway => {
+            tracing::warn!(
+                ?invalid_numbers,
+                "Dialing numbers that are not valid E.164 numbers as configured"
+            );
+            Ok((calls, vec![]))
+        }
+    }
+}
+
+/// Turns the on call persons into the list of calls to place.
+/// Unless `treat_shared_numbers_individually` is set, a number that multiple persons share (e.g. a
+/// team phone) is only called once and attributed to all of them.
+fn plan_calls(
+    people: &[UserPhoneNumber],
+    treat_shared_numbers_individually: bool,
+) -> Vec<PlannedCall> {
+    let mut calls: Vec<PlannedCall> = Vec::new();
+    for person in people {
+        for number in &person.phone {
+            let existing_call = if treat_shared_numbers_individually {
+                None
+            } else {
+                calls.iter().position(|call| call.number.eq(number))
+            };
+            match existing_call {
+                Some(index) => {
+                    tracing::debug!(
+                        number,
+                        person.name,
+                        "Number is shared with another on call person, calling it only once"
+                    );
+                    calls[index].usernames.push(person.name.clone());
+                }
+                None => calls.push(PlannedCall {
+                    number: number.clone(),
+                    usernames: vec![person.name.clone()],
+                }),
+            }
+        }
+    }
+    calls
+}
+
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum OverallResult {
     Success,
     PartialSuccess,
     Failure,
+    // Nobody was alerted, because a maintenance window is active
+    Suppressed,
 }
 
 /// Success when we get back http 200 and active=true in the response
@@ -144,9 +720,25 @@ pub enum OverallResult {
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
 #[serde(rename_all = "camelCase")]
 pub enum DialNumberResult {
-    Success { number: String },
-    Failure { number: String, error: String },
-    Unknown { number: String, status: String },
+    Success {
+        number: String,
+        usernames: Vec<String>,
+    },
+    Failure {
+        number: String,
+        usernames: Vec<String>,
+        error: String,
+        // Only set if error codes are enabled in the config
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        error_code: Option<DialErrorCode>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        twilio_error_code: Option<u32>,
+    },
+    Unknown {
+        number: String,
+        usernames: Vec<String>,
+        status: String,
+    },
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq, Eq, Hash, Clone)]
@@ -154,22 +746,128 @@ pub enum DialNumberResult {
 pub struct AlertResult {
     pub overall_result: OverallResult,
     pub detailed_result: Vec<DialNumberResult>,
+    // Calls that were not placed, because the number is not a valid E.164 number
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_numbers: Vec<PlannedCall>,
+    // Key the caller sent in the `Idempotency-Key` header, repeating the alert with this key
+    // returns this result instead of alerting again, see `IdempotentAlerts`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+    // Why nobody was alerted, only set if the alert was suppressed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub suppressed: Option<String>,
+    // Calls to numbers whose message Twilio rejected, only placed if the SMS fallback is enabled
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub fallbacks: Vec<DialNumberResult>,
+}
+
+/// Results of alerts that were sent with an `Idempotency-Key`. An alert for the same schedule that
+/// is repeated with the same key within `ttl` gets the stored result instead of alerting everybody
+/// again, a repeat that arrives while the first alert is still running waits for its result.
+/// Only alerts that reached somebody are stored, failed and suppressed alerts page again when they
+/// are retried with the same key.
+/// Results are only kept in memory, after a restart a repeated alert pages again.
+#[derive(Debug, Clone)]
+pub struct IdempotentAlerts {
+    results: TtlCache<(Schedule, String), AlertResult>,
+    ttl: Duration,
+}
+
+// Why `IdempotentAlerts::run` didn't store a result
+enum NotStored<E> {
+    Failed(E),
+    // Nobody was reached, so a retry has to alert again
+    Unsuccessful(AlertResult),
+}
+
+impl IdempotentAlerts {
+    pub fn new(ttl: Duration) -> Self {
+        IdempotentAlerts {
+            results: TtlCache::default(),
+            ttl,
+        }
+    }
+
+    /// Runs `alert` unless an alert for this schedule with this key already reached somebody,
+    /// without a key it always runs
+    pub async fn run<F, Fut, E>(
+        &self,
+        schedule: &Schedule,
+        key: Option<&str>,
+        alert: F,
+    ) -> Result<AlertResult, E>
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<AlertResult, E>>,
+    {
+        let Some(key) = key else {
+            return alert().await;
+        };
+        // Keys are usually not seen again, so they have to be dropped once they are expired
+        self.results.remove_stale(self.ttl);
+
+        let mut alerted = false;
+        let result = self
+            .results
+            .get_or_try_insert_with((schedule.clone(), key.to_string()), self.ttl, || {
+                alerted = true;
+                async {
+                    let result = alert().await.map_err(NotStored::Failed)?;
+                    match result.overall_result {
+                        OverallResult::Success | OverallResult::PartialSuccess => Ok(result),
+                        OverallResult::Failure | OverallResult::Suppressed => {
+                            Err(NotStored::Unsuccessful(result))
+                        }
+                    }
+                }
+            })
+            .await;
+        if !alerted {
+            tracing::info!(
+                idempotency_key = key,
+                %schedule,
+                "Alert with this Idempotency-Key was already sent, returning its result"
+            );
+        }
+        match result {
+            Ok(result) | Err(NotStored::Unsuccessful(result)) => Ok(result),
+            Err(NotStored::Failed(e)) => Err(e),
+        }
+    }
 }
 
 impl AlertResult {
     pub fn update_overall_result(&mut self) {
-        let succeeded_calls = self.detailed_result.iter().any(|s| match s {
-            DialNumberResult::Success { .. } => true,
-            _ => false,
-        });
+        let succeeded_calls = self
+            .detailed_result
+            .iter()
+            .chain(&self.fallbacks)
+            .any(|s| match s {
+                DialNumberResult::Success { .. } => true,
+                _ => false,
+            });
 
-        let unknown_calls = self.detailed_result.iter().any(|s| match s {
-            DialNumberResult::Unknown { .. } => true,
-            _ => false,
-        });
+        let unknown_calls = self
+            .detailed_result
+            .iter()
+            .chain(&self.fallbacks)
+            .any(|s| match s {
+                DialNumberResult::Unknown { .. } => true,
+                _ => false,
+            });
 
+        // A rejected message doesn't count once a fallback call reached the number
+        let reached_by_fallback = |number: &str| {
+            self.fallbacks.iter().any(|fallback| match fallback {
+                DialNumberResult::Success {
+                    number: called_number,
+                    ..
+                } => called_number == number,
+                _ => false,
+            })
+        };
         let failed_calls = self.detailed_result.iter().any(|s| match s {
-            DialNumberResult::Failure { .. } => true,
+            DialNumberResult::Failure { number, .. } => !reached_by_fallback(number),
             _ => false,
         });
 
@@ -185,9 +883,22 @@ impl AlertResult {
 
 #[cfg(test)]
 mod test {
-    use super::{AlertResult, DialNumberResult, OverallResult};
+    use super::{
+        classify_failure, describe_failure, filter_by_channel, plan_calls, AlertResult,
+        DialErrorCode, DialNumberResult, Error, IdempotentAlerts, OverallResult, PagingBudget,
+        PlannedCall,
+    };
+    use crate::config::AlertChannel;
+    use crate::opsgenie::{ContactMethod, ContactStatus, PhoneCapabilities, UserPhoneNumber};
+    use crate::util::RateLimiter;
+    use crate::{Schedule, ScheduleRequestByName};
+    use hyper::StatusCode;
     use rstest::rstest;
     use stackable_operator::cluster_resources::ClusterResourceApplyStrategy::Default;
+    use std::collections::VecDeque;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
 
     #[rstest]
     // Order of columns: success, unknown, failed, result
@@ -208,25 +919,309 @@ mod test {
         let mut result = AlertResult {
             overall_result: OverallResult::Success,
             detailed_result: vec![],
+            skipped_numbers: vec![],
+            idempotency_key: None,
+            suppressed: None,
+            fallbacks: vec![],
         };
         if success {
             result.detailed_result.push(DialNumberResult::Success {
                 number: "".to_string(),
+                usernames: vec![],
             })
         };
         if unknown {
             result.detailed_result.push(DialNumberResult::Unknown {
                 number: "".to_string(),
+                usernames: vec![],
                 status: "".to_string(),
             })
         };
         if failed {
             result.detailed_result.push(DialNumberResult::Failure {
                 number: "".to_string(),
+                usernames: vec![],
                 error: "".to_string(),
+                error_code: None,
+                twilio_error_code: None,
             })
         }
         result.update_overall_result();
         assert_eq!(result.overall_result, expected);
     }
+
+    #[rstest]
+    #[case(false, vec![
+        PlannedCall { number: "+49111".to_string(), usernames: vec!["alice".to_string(), "bob".to_string()] },
+        PlannedCall { number: "+49222".to_string(), usernames: vec!["bob".to_string()] },
+    ])]
+    #[case(true, vec![
+        PlannedCall { number: "+49111".to_string(), usernames: vec!["alice".to_string()] },
+        PlannedCall { number: "+49111".to_string(), usernames: vec!["bob".to_string()] },
+        PlannedCall { number: "+49222".to_string(), usernames: vec!["bob".to_string()] },
+    ])]
+    fn test_plan_calls_with_shared_number(
+        #[case] treat_shared_numbers_individually: bool,
+        #[case] expected: Vec<PlannedCall>,
+    ) {
+        let people = vec![
+            UserPhoneNumber {
+                name: "alice".to_string(),
+                phone: vec!["+49111".to_string()],
+                status: ContactStatus::Ok,
+                tier: None,
+                team: None,
+                capabilities: PhoneCapabilities::new(),
+            },
+            UserPhoneNumber {
+                name: "bob".to_string(),
+                phone: vec!["+49111".to_string(), "+49222".to_string()],
+                status: ContactStatus::Ok,
+                tier: None,
+                team: None,
+                capabilities: PhoneCapabilities::new(),
+            },
+        ];
+        assert_eq!(
+            plan_calls(&people, treat_shared_numbers_individually),
+            expected
+        );
+    }
+
+    #[rstest]
+    #[case::voice(AlertChannel::Voice, &["+49111", "+49333"], &["+49222"])]
+    #[case::sms(AlertChannel::Sms, &["+49222", "+49333"], &["+49111"])]
+    #[case::both(AlertChannel::Both, &["+49111", "+49222", "+49333"], &[])]
+    fn test_filter_by_channel(
+        #[case] channel: AlertChannel,
+        #[case] expected_calls: &[&str],
+        #[case] expected_unsupported: &[&str],
+    ) {
+        let people = vec![
+            UserPhoneNumber {
+                name: "alice".to_string(),
+                phone: vec!["+49111".to_string(), "+49222".to_string()],
+                status: ContactStatus::Ok,
+                tier: None,
+                team: None,
+                capabilities: PhoneCapabilities::from([
+                    ("+49111".to_string(), [ContactMethod::Voice].into()),
+                    ("+49222".to_string(), [ContactMethod::Sms].into()),
+                ]),
+            },
+            // Nothing is known about this number, so it is always kept
+            UserPhoneNumber {
+                name: "bob".to_string(),
+                phone: vec!["+49333".to_string()],
+                status: ContactStatus::Ok,
+                tier: None,
+                team: None,
+                capabilities: PhoneCapabilities::new(),
+            },
+        ];
+        let (calls, unsupported) = filter_by_channel(plan_calls(&people, false), &people, channel);
+        let numbers = |calls: Vec<PlannedCall>| {
+            calls
+                .into_iter()
+                .map(|call| call.number)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(numbers(calls), expected_calls);
+        assert_eq!(numbers(unsupported), expected_unsupported);
+    }
+
+    #[rstest]
+    #[case(
+        r#"{"code": 21219, "message": "Trial accounts cannot call unverified numbers", "status": 400}"#,
+        Some("Twilio trial account can only call verified numbers, verify [+4912345] in the Twilio console or upgrade the account")
+    )]
+    #[case(
+        r#"{"code": 20404, "message": "The requested resource was not found", "status": 404}"#,
+        None
+    )]
+    #[case("not json", None)]
+    fn test_describe_failure(#[case] body: &str, #[case] expected: Option<&str>) {
+        let error = crate::util::Error::HttpErrorResponse {
+            status: StatusCode::BAD_REQUEST,
+            url: "https://studio.twilio.com/v2/Flows/".to_string(),
+            text: body.to_string(),
+        };
+        // Anything that isn't a trial account restriction keeps the original error message
+        let expected = expected.map_or(error.to_string(), String::from);
+        assert_eq!(describe_failure(&error, "+4912345"), expected);
+    }
+
+    #[rstest]
+    #[case(
+        r#"{"code": 21608, "message": "The number is unverified", "status": 400}"#,
+        (DialErrorCode::TrialAccountRestriction, Some(21608))
+    )]
+    #[case(
+        r#"{"code": 21211, "message": "Invalid 'To' Phone Number", "status": 400}"#,
+        (DialErrorCode::InvalidNumber, Some(21211))
+    )]
+    #[case(
+        r#"{"code": 21610, "message": "Attempt to send to unsubscribed recipient", "status": 400}"#,
+        (DialErrorCode::CarrierRejected, Some(21610))
+    )]
+    #[case(
+        r#"{"code": 20404, "message": "The requested resource was not found", "status": 404}"#,
+        (DialErrorCode::TwilioError, Some(20404))
+    )]
+    #[case("not json", (DialErrorCode::RequestFailed, None))]
+    fn test_classify_failure(#[case] body: &str, #[case] expected: (DialErrorCode, Option<u32>)) {
+        let error = crate::util::Error::HttpErrorResponse {
+            status: StatusCode::BAD_REQUEST,
+            url: "https://studio.twilio.com/v2/Flows/".to_string(),
+            text: body.to_string(),
+        };
+        assert_eq!(classify_failure(&error), expected);
+    }
+
+    #[rstest]
+    #[case::fallback_reached_number(true, OverallResult::Success)]
+    #[case::fallback_failed(false, OverallResult::Failure)]
+    fn test_update_overall_status_with_sms_fallback(
+        #[case] fallback_succeeded: bool,
+        #[case] expected: OverallResult,
+    ) {
+        let failure = || DialNumberResult::Failure {
+            number: "+49111".to_string(),
+            usernames: vec!["alice".to_string()],
+            error: "Twilio rejected the message".to_string(),
+            error_code: None,
+            twilio_error_code: None,
+        };
+        let fallback = if fallback_succeeded {
+            DialNumberResult::Success {
+                number: "+49111".to_string(),
+                usernames: vec!["alice".to_string()],
+            }
+        } else {
+            failure()
+        };
+        let mut result = AlertResult {
+            overall_result: OverallResult::Success,
+            detailed_result: vec![failure()],
+            skipped_numbers: vec![],
+            idempotency_key: None,
+            suppressed: None,
+            fallbacks: vec![fallback],
+        };
+        result.update_overall_result();
+        assert_eq!(result.overall_result, expected);
+    }
+
+    #[test]
+    fn test_paging_budget() {
+        let budget = PagingBudget {
+            budget: Some(3),
+            window: Duration::from_secs(3600),
+            spent: Arc::new(Mutex::new(VecDeque::new())),
+            exceeded_reports: RateLimiter::new(1, Duration::from_secs(3600)),
+        };
+        // Simulated alerts don't spend anything
+        budget.check(3).unwrap();
+        budget.try_spend(2).unwrap();
+        assert!(budget.check(2).is_err());
+        // Refused alerts don't use up the remaining budget
+        assert!(matches!(
+            budget.try_spend(2),
+            Err(Error::PagingBudgetExceeded { remaining: 1, .. })
+        ));
+        budget.try_spend(1).unwrap();
+        assert!(budget.try_spend(1).is_err());
+
+        let expired = PagingBudget {
+            budget: Some(1),
+            window: Duration::ZERO,
+            spent: Arc::new(Mutex::new(VecDeque::new())),
+            exceeded_reports: RateLimiter::new(1, Duration::ZERO),
+        };
+        expired.try_spend(1).unwrap();
+        expired.try_spend(1).unwrap();
+
+        // Only the first refused alert within the window is reported
+        assert!(budget.report_exceeded());
+        assert!(!budget.report_exceeded());
+    }
+
+    fn schedule(name: &str) -> Schedule {
+        Schedule::ScheduleByName(ScheduleRequestByName {
+            name: name.to_string(),
+        })
+    }
+
+    fn alert_result(overall_result: OverallResult) -> AlertResult {
+        AlertResult {
+            overall_result,
+            detailed_result: vec![],
+            skipped_numbers: vec![],
+            idempotency_key: Some("retry-me".to_string()),
+            suppressed: None,
+            fallbacks: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_alerts_with_same_idempotency_key_are_sent_once() {
+        let idempotent_alerts = IdempotentAlerts::new(Duration::from_secs(60));
+        let ops = schedule("ops");
+        let alerts_sent = &AtomicUsize::new(0);
+        let send_alert = || async move {
+            alerts_sent.fetch_add(1, Ordering::SeqCst);
+            Ok::<_, ()>(alert_result(OverallResult::Success))
+        };
+
+        let first = idempotent_alerts
+            .run(&ops, Some("retry-me"), send_alert)
+            .await;
+        let repeated = idempotent_alerts
+            .run(&ops, Some("retry-me"), send_alert)
+            .await;
+        assert_eq!(alerts_sent.load(Ordering::SeqCst), 1);
+        assert_eq!(first, repeated);
+
+        // Other keys, other schedules and alerts without a key are sent as usual
+        idempotent_alerts
+            .run(&ops, Some("another-key"), send_alert)
+            .await
+            .unwrap();
+        idempotent_alerts
+            .run(&schedule("dev"), Some("retry-me"), send_alert)
+            .await
+            .unwrap();
+        idempotent_alerts.run(&ops, None, send_alert).await.unwrap();
+        assert_eq!(alerts_sent.load(Ordering::SeqCst), 4);
+    }
+
+    #[rstest]
+    #[case::nobody_reached(Ok(OverallResult::Failure))]
+    #[case::suppressed(Ok(OverallResult::Suppressed))]
+    #[case::error(Err(()))]
+    #[tokio::test]
+    async fn test_retried_alert_pages_again_unless_it_reached_somebody(
+        #[case] outcome: Result<OverallResult, ()>,
+    ) {
+        let idempotent_alerts = IdempotentAlerts::new(Duration::from_secs(60));
+        let ops = schedule("ops");
+        let alerts_sent = &AtomicUsize::new(0);
+
+        let first = idempotent_alerts
+            .run(&ops, Some("retry-me"), || async {
+                alerts_sent.fetch_add(1, Ordering::SeqCst);
+                outcome.clone().map(alert_result)
+            })
+            .await;
+        assert_eq!(first, outcome.map(alert_result));
+
+        let retried = idempotent_alerts
+            .run(&ops, Some("retry-me"), || async {
+                alerts_sent.fetch_add(1, Ordering::SeqCst);
+                Ok::<_, ()>(alert_result(OverallResult::Success))
+            })
+            .await;
+        assert_eq!(retried, Ok(alert_result(OverallResult::Success)));
+        assert_eq!(alerts_sent.load(Ordering::SeqCst), 2);
+    }
 }
@@ -2,6 +2,27 @@ use hyper::StatusCode;
 use reqwest::{RequestBuilder, Response};
 use serde::de::DeserializeOwned;
 use snafu::{ResultExt, Snafu};
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The `tracing` macros need to know the level at compile time, this picks the matching macro for a
+/// level that is only known at runtime (e.g. because it is configurable)
+macro_rules! event_at_level {
+    ($level:expr, $($args:tt)+) => {
+        match $level {
+            tracing::Level::ERROR => tracing::error!($($args)+),
+            tracing::Level::WARN => tracing::warn!($($args)+),
+            tracing::Level::INFO => tracing::info!($($args)+),
+            tracing::Level::DEBUG => tracing::debug!($($args)+),
+            _ => tracing::trace!($($args)+),
+        }
+    };
+}
+pub(crate) use event_at_level;
 
 #[derive(Snafu, Debug)]
 pub enum Error {
@@ -26,6 +47,33 @@ pub enum Error {
     },
 }
 
+impl Error {
+    /// Returns true if the server responded with 404, which callers usually want to handle
+    /// differently from other errors.
+    pub fn is_not_found(&self) -> bool {
+        match self {
+            Error::HttpErrorResponse { status, .. }
+            | Error::HttpErrorResponseUndecodableText { status, .. } => {
+                *status == StatusCode::NOT_FOUND
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Checks that a phone number is in E.164 format, a `+` followed by up to 15 digits without a
+/// leading zero
+pub fn is_e164(number: &str) -> bool {
+    match number.strip_prefix('+') {
+        Some(digits) => {
+            (2..=15).contains(&digits.len())
+                && !digits.starts_with('0')
+                && digits.chars().all(|c| c.is_ascii_digit())
+        }
+        None => false,
+    }
+}
+
 pub async fn send_json_request<T: DeserializeOwned>(req: RequestBuilder) -> Result<T, Error> {
     // make the request
     let response = req.send().await.context(HttpRequestSnafu)?;
@@ -62,3 +110,199 @@ async fn error_for_status(response: Response) -> Result<Response, Error> {
     }
     Ok(response)
 }
+
+/// Minimal in-memory cache shared between requests, entries are considered stale once they are
+/// older than the `max_age` passed when reading them.
+#[derive(Debug, Clone)]
+pub struct TtlCache<K, V> {
+    entries: Arc<Mutex<HashMap<K, (Instant, V)>>>,
+    // One lock per key that is currently being fetched, see `get_or_try_insert_with`
+    in_flight: Arc<Mutex<HashMap<K, Arc<tokio::sync::Mutex<()>>>>>,
+}
+
+impl<K, V> Default for TtlCache<K, V> {
+    fn default() -> Self {
+        Self {
+            entries: Arc::new(Mutex::new(HashMap::new())),
+            in_flight: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+}
+
+impl<K: Eq + Hash, V: Clone> TtlCache<K, V> {
+    pub fn get<Q>(&self, key: &Q, max_age: Duration) -> Option<V>
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|(fetched_at, _)| fetched_at.elapsed() < max_age)
+            .map(|(_, value)| value.clone())
+    }
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key, (Instant::now(), value));
+    }
+
+    /// Returns the cached value, or fetches and caches it if there is no fresh value.
+    /// Concurrent calls for the same key only fetch once, the others wait for that fetch and then
+    /// use its result, so a burst of requests on a cold cache doesn't turn into a burst of requests
+    /// upstream. If the fetch fails, the next waiting call tries again.
+    pub async fn get_or_try_insert_with<F, Fut, E>(
+        &self,
+        key: K,
+        max_age: Duration,
+        fetch: F,
+    ) -> Result<V, E>
+    where
+        K: Clone,
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<V, E>>,
+    {
+        if let Some(value) = self.get(&key, max_age) {
+            return Ok(value);
+        }
+
+        let key_lock = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+        let result = {
+            let _fetching = key_lock.lock().await;
+            // Whoever held the lock before us might have fetched the value already
+            match self.get(&key, max_age) {
+                Some(value) => Ok(value),
+                None => {
+                    let result = fetch().await;
+                    if let Ok(value) = &result {
+                        self.insert(key.clone(), value.clone());
+                    }
+                    result
+                }
+            }
+        };
+
+        // Clean up if nobody else is waiting for this key anymore
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if Arc::strong_count(&key_lock) == 2 {
+            in_flight.remove(&key);
+        }
+        result
+    }
+
+    /// Drops all entries that are older than `max_age`, for caches whose keys are rarely looked up
+    /// again
+    pub fn remove_stale(&self, max_age: Duration) {
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, (fetched_at, _)| fetched_at.elapsed() < max_age);
+    }
+
+    pub fn invalidate<Q>(&self, key: &Q)
+    where
+        K: Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(key);
+    }
+}
+
+/// Allows at most `limit` requests per key within a rolling window
+#[derive(Debug, Clone)]
+pub struct RateLimiter<K> {
+    limit: usize,
+    window: Duration,
+    // When the requests within the current window were made, oldest first
+    requests: Arc<Mutex<HashMap<K, VecDeque<Instant>>>>,
+}
+
+impl<K: Eq + Hash> RateLimiter<K> {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        RateLimiter {
+            limit,
+            window,
+            requests: Arc::default(),
+        }
+    }
+
+    /// Counts a request for this key, returns false without counting it if the limit is reached
+    pub fn try_acquire(&self, key: K) -> bool {
+        let now = Instant::now();
+        let mut requests = self.requests.lock().unwrap();
+        // Forget keys without recent requests, so the map doesn't grow with every key ever seen
+        requests.retain(|_, made_at| {
+            while made_at
+                .front()
+                .is_some_and(|made_at| now.duration_since(*made_at) >= self.window)
+            {
+                made_at.pop_front();
+            }
+            !made_at.is_empty()
+        });
+        let made_at = requests.entry(key).or_default();
+        if made_at.len() >= self.limit {
+            return false;
+        }
+        made_at.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{is_e164, RateLimiter, TtlCache};
+    use rstest::rstest;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[rstest]
+    #[case("+4917012345678", true)]
+    #[case("+12025550123", true)]
+    #[case("4917012345678", false)]
+    #[case("+04917012345678", false)]
+    #[case("+49 170 12345678", false)]
+    #[case("+491701234567890123", false)]
+    #[case("+", false)]
+    fn test_is_e164(#[case] number: &str, #[case] expected: bool) {
+        assert_eq!(is_e164(number), expected);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_fetches_are_deduplicated() {
+        let cache: TtlCache<String, String> = TtlCache::default();
+        let fetches = &AtomicUsize::new(0);
+
+        let requests = (0..10).map(|_| {
+            cache.get_or_try_insert_with(
+                "ops".to_string(),
+                Duration::from_secs(60),
+                move || async move {
+                    fetches.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    Ok::<_, ()>("schedule-id".to_string())
+                },
+            )
+        });
+        let results = futures::future::join_all(requests).await;
+
+        assert_eq!(fetches.load(Ordering::SeqCst), 1);
+        assert!(results
+            .into_iter()
+            .all(|result| result == Ok("schedule-id".to_string())));
+    }
+
+    #[test]
+    fn test_rate_limiter_is_per_key() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        assert!(limiter.try_acquire("alice"));
+        assert!(limiter.try_acquire("alice"));
+        assert!(!limiter.try_acquire("alice"));
+        assert!(limiter.try_acquire("bob"));
+    }
+}